@@ -0,0 +1,813 @@
+use crate::{
+    config, connection, error, proxy_protocol, request, response, router, shutdown, stats, utils,
+    WebServer,
+};
+use std::{
+    future::Future,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net, task, time,
+};
+
+// The parts of the `AsyncWebServer` its accepting tasks and the connections they serve need
+#[derive(Debug, Clone)]
+struct ServerContext {
+    router: Arc<router::WebRouter>,
+    config: Arc<config::ServerConfig>,
+    shutdown: Arc<shutdown::ShutdownState>,
+    counters: Arc<stats::ServerCounters>,
+}
+
+// A `WebServer` which accepts connections and reads requests off them on a tokio runtime, so
+// that idle keep-alive connections do not each tie up a worker thread. It uses the same
+// `WebRouter`, `Request`, `Response` and `Context` as the `WebServer` it is created from with
+// `WebServer::into_async`, the handlers run on tokio's blocking threads since they are
+// synchronous code.
+//
+// It follows the `ServerConfig` of the server, except for `min_transfer_rate`, which is not
+// enforced, and `OverloadPolicy`, as there is no queue of connections to run full.
+#[derive(Debug)]
+pub struct AsyncWebServer {
+    listeners: Vec<connection::Listener>,
+    pub hide_banner: bool,
+    pub addresses: Vec<String>,
+    #[cfg(unix)]
+    unix_socket_paths: Vec<std::path::PathBuf>,
+    pub config: config::ServerConfig,
+    router: Arc<router::WebRouter>,
+    shutdown: Arc<shutdown::ShutdownState>,
+    counters: Arc<stats::ServerCounters>,
+}
+
+impl AsyncWebServer {
+    pub(crate) fn from_server(server: WebServer) -> Result<AsyncWebServer, error::WebServerError> {
+        if server.config.tls_enabled() {
+            return Err(error::WebServerError::ConfigError(
+                "TLS is not supported by the async server".to_string(),
+            ));
+        }
+        return Ok(AsyncWebServer {
+            listeners: server.listeners,
+            hide_banner: server.hide_banner,
+            addresses: server.addresses,
+            #[cfg(unix)]
+            unix_socket_paths: server.unix_socket_paths,
+            config: server.config,
+            router: server.router,
+            shutdown: server.shutdown,
+            counters: server.counters,
+        });
+    }
+
+    // This method returns a handle which can be used to shut the server down gracefully, see
+    // `ServerHandle::shutdown`
+    pub fn handle(&self) -> shutdown::ServerHandle {
+        return shutdown::ServerHandle::new(Arc::clone(&self.shutdown), Arc::clone(&self.counters));
+    }
+
+    // This method starts the server on the tokio runtime it is awaited on, every listener gets a
+    // task accepting connections on it and every accepted connection is served by a task of its
+    // own. Like `WebServer::listen` it runs until a shutdown is requested through a
+    // `ServerHandle`, then it waits for the connections still being served (for at most
+    // `shutdown_timeout`) and returns.
+    pub async fn listen(&mut self) {
+        if !self.hide_banner {
+            println!(
+                "-----> HTTP server running on {} (async)",
+                self.addresses.join(", ")
+            );
+        }
+
+        let context = ServerContext {
+            router: Arc::clone(&self.router),
+            config: Arc::new(self.config.clone()),
+            shutdown: Arc::clone(&self.shutdown),
+            counters: Arc::clone(&self.counters),
+        };
+
+        let mut accept_tasks = task::JoinSet::new();
+        for listener in &self.listeners {
+            match listener.local_addr() {
+                Ok(address) => self.shutdown.register(address),
+                Err(e) => eprintln!(
+                    "Failed to get the local address of the listener, Error: {}",
+                    e
+                ),
+            };
+            match listener.try_clone() {
+                Ok(listener) => {
+                    accept_tasks.spawn(Self::accept_connections(listener, context.clone()));
+                }
+                Err(e) => eprintln!("Failed to register the listener, Error: {}", e),
+            };
+        }
+        while accept_tasks.join_next().await.is_some() {}
+
+        // graceful shutdown, let the tasks finish the requests in flight
+        let deadline = Instant::now() + context.config.shutdown_timeout;
+        while self.counters.snapshot().active_connections > 0 && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let active_connections = self.counters.snapshot().active_connections;
+        if active_connections > 0 {
+            eprintln!(
+                "Shutdown timed out with {} requests still in flight",
+                active_connections
+            );
+        }
+        #[cfg(unix)]
+        connection::remove_unix_sockets(&self.unix_socket_paths);
+    }
+
+    // This function accepts the connections arriving on `listener` until a shutdown is requested
+    async fn accept_connections(listener: connection::Listener, context: ServerContext) {
+        match listener {
+            connection::Listener::Tcp(listener) => {
+                let listener = match listener.set_nonblocking(true) {
+                    Ok(_) => net::TcpListener::from_std(listener),
+                    Err(e) => Err(e),
+                };
+                let listener = match listener {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        eprintln!("Failed to register the listener, Error: {}", e);
+                        return;
+                    }
+                };
+                loop {
+                    // see `WebServer::accept_connections`
+                    if context.shutdown.is_requested() {
+                        break;
+                    }
+                    let stream = listener.accept().await;
+                    if context.shutdown.is_requested() {
+                        break;
+                    }
+                    match stream {
+                        Ok((stream, _)) => Self::spawn_connection(&context, stream),
+                        Err(e) => eprintln!("Failed to establish a connection, Error: {}", e),
+                    };
+                }
+            }
+            #[cfg(unix)]
+            connection::Listener::Unix(listener) => {
+                let listener = match listener.set_nonblocking(true) {
+                    Ok(_) => net::UnixListener::from_std(listener),
+                    Err(e) => Err(e),
+                };
+                let listener = match listener {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        eprintln!("Failed to register the listener, Error: {}", e);
+                        return;
+                    }
+                };
+                loop {
+                    // see `WebServer::accept_connections`
+                    if context.shutdown.is_requested() {
+                        break;
+                    }
+                    let stream = listener.accept().await;
+                    if context.shutdown.is_requested() {
+                        break;
+                    }
+                    match stream {
+                        Ok((stream, _)) => Self::spawn_connection(&context, stream),
+                        Err(e) => eprintln!("Failed to establish a connection, Error: {}", e),
+                    };
+                }
+            }
+        }
+    }
+
+    fn spawn_connection<S>(context: &ServerContext, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + AsyncStream + Unpin + Send + 'static,
+    {
+        let router = Arc::clone(&context.router);
+        let config = Arc::clone(&context.config);
+        let shutdown = Arc::clone(&context.shutdown);
+        let counters = Arc::clone(&context.counters);
+        context.counters.connection_opened();
+        task::spawn(async move {
+            match Self::handle_request(router, config, shutdown, &counters, stream).await {
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("Failed to handle incoming request, Error: {}", e);
+                }
+            };
+            counters.connection_closed();
+        });
+    }
+
+    // This function serves the requests arriving on a connection one after the other, the same
+    // way `WebServer::handle_request` does
+    async fn handle_request<S>(
+        router: Arc<router::WebRouter>,
+        config: Arc<config::ServerConfig>,
+        shutdown: Arc<shutdown::ShutdownState>,
+        counters: &stats::ServerCounters,
+        stream: S,
+    ) -> Result<(), error::WebServerError>
+    where
+        S: AsyncRead + AsyncWrite + AsyncStream + Unpin,
+    {
+        let remote_addr = stream.peer_addr().ok();
+        let local_addr = stream.local_addr().ok();
+        // responses are written to a buffer first, so that the responses to pipelined requests
+        // which are already buffered go out together, see `WebServer::handle_request`
+        let mut buf_reader = tokio::io::BufReader::new(tokio::io::BufWriter::new(stream));
+        let mut served_requests = 0;
+        let mut proxy = None;
+        if config.proxy_protocol {
+            match timed(
+                config.header_read_timeout,
+                read_proxy_header(&mut buf_reader),
+            )
+            .await
+            {
+                Ok(header) => proxy = header,
+                Err(e) => return Err(error::WebServerError::ProxyProtocolError(e)),
+            };
+        }
+
+        loop {
+            if served_requests > 0 {
+                match Self::wait_for_request(&mut buf_reader, &config, &shutdown).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        return match timed(config.write_timeout, buf_reader.get_mut().flush()).await
+                        {
+                            Ok(_) => Ok(()),
+                            Err(e) => Err(error::WebServerError::IO(e)),
+                        };
+                    }
+                    Err(e) => return Err(error::WebServerError::IO(e)),
+                };
+            }
+
+            let mut request = match Self::read_request_head(&mut buf_reader, &config).await {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(e) => return Self::reject_request(&mut buf_reader, &config, counters, e).await,
+            };
+            match router.check_expectation(&request, &config.limits) {
+                Ok(router::Expectation::None) => {}
+                Ok(router::Expectation::Continue) => {
+                    let stream = buf_reader.get_mut();
+                    timed(config.write_timeout, stream.write_all(response::CONTINUE)).await?;
+                    timed(config.write_timeout, stream.flush()).await?;
+                }
+                // the client may not wait for `100 Continue` any longer and be sending the body
+                // already, see `AsyncWebServer::linger`
+                Ok(router::Expectation::Reject(mut response)) => {
+                    response.set_keep_alive(false);
+                    let chunked = request.version != "HTTP/1.0";
+                    let stream = buf_reader.get_mut();
+                    match Self::write_response(stream, &response, chunked, &config).await {
+                        Ok(_) => {
+                            Self::linger(&mut buf_reader).await;
+                            return Ok(());
+                        }
+                        Err(e) => return Err(error::WebServerError::IO(e)),
+                    };
+                }
+                Err(e) => return Self::reject_request(&mut buf_reader, &config, counters, e).await,
+            };
+            match Self::read_request_body(&mut buf_reader, &config, &mut request).await {
+                Ok(_) => {}
+                Err(e) => return Self::reject_request(&mut buf_reader, &config, counters, e).await,
+            };
+            served_requests += 1;
+            request.remote_addr = remote_addr.clone();
+            request.local_addr = local_addr.clone();
+            request.proxy = proxy.clone();
+            request.resolve_client_ip(&config.trusted_proxies);
+            request.shutdown = Some(Arc::clone(&shutdown));
+
+            let keep_alive = config.keep_alive
+                && request.keep_alive()
+                && (config.max_requests_per_connection == 0
+                    || served_requests < config.max_requests_per_connection);
+            let chunked = request.version != "HTTP/1.0";
+
+            // the handlers are blocking code, they are run where they can not stall the runtime
+            let handler_router = Arc::clone(&router);
+            let mut response =
+                match task::spawn_blocking(move || handler_router.handle_request(request)).await {
+                    Ok(Ok(res)) => res,
+                    Ok(Err(e)) => {
+                        return Err(error::WebServerError::InternalServerError(e.to_string()));
+                    }
+                    Err(e) => {
+                        return Err(error::WebServerError::InternalServerError(e.to_string()));
+                    }
+                };
+            let keep_alive = response.set_keep_alive(
+                keep_alive
+                    && !shutdown.is_requested()
+                    && (chunked || matches!(response.body, response::Body::Full(_))),
+            );
+
+            // pipelined requests are answered in the order they came in, while the next one is
+            // already buffered the response is held back to go out together with the next ones
+            let mut result =
+                Self::write_response(buf_reader.get_mut(), &response, chunked, &config).await;
+            if result.is_ok() && (!keep_alive || buf_reader.buffer().is_empty()) {
+                result = timed(config.write_timeout, buf_reader.get_mut().flush()).await;
+            }
+            match result {
+                Ok(_) => {}
+                Err(e) => {
+                    let e = error::WebServerError::IO(e);
+                    if utils::is_timeout(&e) {
+                        counters.connection_timed_out();
+                    }
+                    return Err(e);
+                }
+            };
+            counters.request_handled();
+
+            // a handler taking the connection over gets it as a blocking stream, on a blocking
+            // thread, along with what the client already sent past the request
+            if let Some(upgrade) = response.upgrade.as_ref().and_then(|upgrade| upgrade.take()) {
+                let buffered = buf_reader.buffer().to_vec();
+                let stream = match buf_reader.into_inner().into_inner().into_stream() {
+                    Ok(stream) => stream,
+                    Err(e) => return Err(error::WebServerError::IO(e)),
+                };
+                stream.set_write_timeout(Some(config.write_timeout))?;
+                let upgraded = connection::Upgraded::new(
+                    connection::TimedStream::new(stream),
+                    buffered,
+                    config.limits.max_body_size,
+                    Arc::clone(&shutdown),
+                );
+                match task::spawn_blocking(move || (upgrade)(upgraded)).await {
+                    Ok(_) => return Ok(()),
+                    Err(e) => {
+                        return Err(error::WebServerError::InternalServerError(e.to_string()));
+                    }
+                }
+            }
+
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    // This function waits for the first bytes of the next request on an idle persistent
+    // connection, it returns `false` if the connection should be closed instead. See
+    // `WebServer::wait_for_request`
+    async fn wait_for_request<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        config: &config::ServerConfig,
+        shutdown: &shutdown::ShutdownState,
+    ) -> io::Result<bool> {
+        let deadline = Instant::now() + config.keep_alive_timeout;
+        loop {
+            if shutdown.is_requested() {
+                return Ok(false);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            let slice = (deadline - now).min(Duration::from_millis(100));
+            match time::timeout(slice, reader.fill_buf()).await {
+                Ok(Ok(buf)) => return Ok(!buf.is_empty()),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {}
+            };
+        }
+    }
+
+    // These functions read the next request off the connection. The raw head and body are read
+    // asynchronously under the same limits `Request::read_from` applies and are then parsed by
+    // it, so both servers accept and reject exactly the same requests
+    async fn read_request_head<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        config: &config::ServerConfig,
+    ) -> Result<Option<request::Request>, error::WebServerError> {
+        let limits = &config.limits;
+        let head = match timed(config.header_read_timeout, Self::read_head(reader, limits)).await {
+            Ok(Some(head)) => head,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        return request::Request::read_head(&mut head.as_slice(), limits);
+    }
+
+    async fn read_request_body<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        config: &config::ServerConfig,
+        request: &mut request::Request,
+    ) -> Result<(), error::WebServerError> {
+        let limits = &config.limits;
+        let body = match request.body_framing() {
+            Ok(request::BodyFraming::Empty) => return Ok(()),
+            Ok(request::BodyFraming::ContentLength(content_length)) => {
+                if content_length > limits.max_body_size {
+                    return Err(error::WebServerError::RequestParseError(
+                        error::RequestError::PayloadTooLargeError(limits.max_body_size),
+                    ));
+                }
+                let mut body = vec![0; content_length];
+                let mut filled = 0;
+                while filled < content_length {
+                    match timed(config.body_read_timeout, reader.read(&mut body[filled..])).await {
+                        Ok(0) => {
+                            return Err(error::WebServerError::IO(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "connection closed in the middle of the request body",
+                            )));
+                        }
+                        Ok(n) => filled += n,
+                        Err(e) => return Err(error::WebServerError::IO(e)),
+                    };
+                }
+                body
+            }
+            Ok(request::BodyFraming::Chunked) => {
+                match Self::read_chunked_body(reader, config).await {
+                    Ok(body) => body,
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(e) => return Err(error::WebServerError::RequestParseError(e)),
+        };
+        return request.read_body(&mut body.as_slice(), limits);
+    }
+
+    // This function reads the raw request line and headers, up to (and including) the empty line
+    // which ends them. `None` is returned at EOF before the first byte of the request, a head cut
+    // short (by EOF or by going over `max_header_count` or `max_header_size`) is returned as is
+    // for `Request::read_head` to report
+    async fn read_head<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        limits: &config::RequestLimits,
+    ) -> Result<Option<Vec<u8>>, error::WebServerError> {
+        let mut head = Vec::new();
+        let mut lines = 0;
+        let mut header_size = 0;
+        loop {
+            let line_length = match lines {
+                0 => {
+                    read_line(
+                        reader,
+                        &mut head,
+                        limits.max_request_line_length,
+                        error::RequestError::RequestLineTooLongError(
+                            limits.max_request_line_length,
+                        ),
+                    )
+                    .await?
+                }
+                _ => {
+                    read_line(
+                        reader,
+                        &mut head,
+                        limits.max_header_size.saturating_sub(header_size) + 2,
+                        error::RequestError::HeaderFieldsTooLargeError(format!(
+                            "more than {} bytes",
+                            limits.max_header_size
+                        )),
+                    )
+                    .await?
+                }
+            };
+            let line_length = match line_length {
+                Some(line_length) => line_length,
+                None if head.is_empty() => return Ok(None),
+                None => return Ok(Some(head)),
+            };
+            if line_length == 0 {
+                return Ok(Some(head));
+            }
+            if lines > 0 {
+                header_size += line_length + 2;
+            }
+            lines += 1;
+            if lines > limits.max_header_count + 1 || header_size > limits.max_header_size {
+                return Ok(Some(head));
+            }
+        }
+    }
+
+    // This function reads a raw chunked body, chunk by chunk, for `chunked::decode` to decode. A
+    // malformed or too large body is returned as far as it was read, `chunked::decode` reports
+    // what is wrong with it
+    async fn read_chunked_body<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        config: &config::ServerConfig,
+    ) -> Result<Vec<u8>, error::WebServerError> {
+        let limits = &config.limits;
+        let mut raw = Vec::new();
+        let mut body_size = 0;
+        loop {
+            let start = raw.len();
+            let size_line = timed(
+                config.body_read_timeout,
+                read_line(
+                    reader,
+                    &mut raw,
+                    utils::chunked::MAX_CHUNK_SIZE_LINE_LENGTH,
+                    error::RequestError::InvalidChunkedBodyError(
+                        "chunk size line is too long".to_string(),
+                    ),
+                ),
+            )
+            .await?;
+            if size_line.is_none() {
+                return Ok(raw);
+            }
+            let size =
+                match utils::chunked::parse_size_line(&String::from_utf8_lossy(&raw[start..])) {
+                    Some(size) => size,
+                    None => return Ok(raw),
+                };
+            if size == 0 {
+                break;
+            }
+            if size > limits.max_body_size - body_size {
+                return Ok(raw);
+            }
+            body_size += size;
+
+            let start = raw.len();
+            raw.resize(start + size, 0);
+            timed(
+                config.body_read_timeout,
+                reader.read_exact(&mut raw[start..]),
+            )
+            .await?;
+            timed(
+                config.body_read_timeout,
+                read_line(
+                    reader,
+                    &mut raw,
+                    2,
+                    error::RequestError::InvalidChunkedBodyError(
+                        "chunk data is longer than its declared size".to_string(),
+                    ),
+                ),
+            )
+            .await?;
+        }
+
+        // the trailer fields, up to the empty line which ends the chunked body
+        let mut trailers = 0;
+        let mut trailers_size = 0;
+        loop {
+            let line_length = timed(
+                config.body_read_timeout,
+                read_line(
+                    reader,
+                    &mut raw,
+                    limits.max_header_size.saturating_sub(trailers_size),
+                    error::RequestError::HeaderFieldsTooLargeError(format!(
+                        "trailer fields are larger than {} bytes",
+                        limits.max_header_size
+                    )),
+                ),
+            )
+            .await?;
+            match line_length {
+                Some(0) | None => return Ok(raw),
+                Some(line_length) => trailers_size += line_length + 2,
+            };
+            trailers += 1;
+            if trailers > limits.max_header_count {
+                return Ok(raw);
+            }
+        }
+    }
+
+    // This function writes a response to the connection, the chunks of a streaming body are
+    // pulled on a blocking thread as they may come from blocking code (e.g. a file). What is
+    // written last is left for the caller to flush
+    async fn write_response<W: AsyncWrite + Unpin>(
+        writer: &mut W,
+        response: &response::Response,
+        chunked: bool,
+        config: &config::ServerConfig,
+    ) -> io::Result<()> {
+        let write_timeout = config.write_timeout;
+        let stream = match &response.body {
+            response::Body::Stream(stream) if !response.omit_body => stream,
+            _ => {
+                let mut buffer = Vec::new();
+                response.write_to(&mut buffer, chunked)?;
+                return timed(write_timeout, writer.write_all(&buffer)).await;
+            }
+        };
+
+        timed(
+            write_timeout,
+            writer.write_all(response.head_string(chunked).as_bytes()),
+        )
+        .await?;
+        timed(write_timeout, writer.flush()).await?;
+        if let Some(mut chunks) = stream.take() {
+            loop {
+                let (chunk, rest) = match task::spawn_blocking(move || {
+                    let chunk = chunks.next();
+                    (chunk, chunks)
+                })
+                .await
+                {
+                    Ok(next) => next,
+                    Err(e) => return Err(io::Error::other(e.to_string())),
+                };
+                chunks = rest;
+                let chunk = match chunk {
+                    Some(chunk) => chunk?,
+                    None => break,
+                };
+                let mut buffer = Vec::new();
+                match chunked {
+                    true => utils::chunked::write_chunk(&mut buffer, &chunk)?,
+                    false => buffer = chunk,
+                };
+                timed(write_timeout, writer.write_all(&buffer)).await?;
+                timed(write_timeout, writer.flush()).await?;
+            }
+        }
+        if chunked {
+            let mut buffer = Vec::new();
+            utils::chunked::write_last_chunk(&mut buffer)?;
+            timed(write_timeout, writer.write_all(&buffer)).await?;
+        }
+        return Ok(());
+    }
+
+    // This function answers a request which could not be read with an error response, see
+    // `WebServer::reject_request`
+    async fn reject_request<S: AsyncRead + AsyncWrite + Unpin>(
+        buf_reader: &mut tokio::io::BufReader<S>,
+        config: &config::ServerConfig,
+        counters: &stats::ServerCounters,
+        e: error::WebServerError,
+    ) -> Result<(), error::WebServerError> {
+        let status_code = match &e {
+            error::WebServerError::RequestParseError(parse_error) => parse_error.status_code(),
+            _ if utils::is_timeout(&e) => {
+                counters.connection_timed_out();
+                utils::HttpStatusCode::RequestTimeout
+            }
+            _ => return Err(e),
+        };
+        let mut response =
+            response::Response::new(status_code.clone(), status_code.code().0.to_string());
+        response.set_keep_alive(false);
+        let stream = buf_reader.get_mut();
+        if Self::write_response(stream, &response, false, config)
+            .await
+            .is_ok()
+        {
+            Self::linger(buf_reader).await;
+        }
+        return Err(e);
+    }
+
+    // This function writes out the response and lingers on the connection before it is closed,
+    // the same way `connection::Stream::linger` does
+    async fn linger<S: AsyncRead + AsyncWrite + Unpin>(buf_reader: &mut tokio::io::BufReader<S>) {
+        let deadline = Instant::now() + connection::LINGER_TIMEOUT;
+        if timed(connection::LINGER_TIMEOUT, buf_reader.get_mut().shutdown())
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let mut buffer = [0; 4096];
+        let mut drained = 0;
+        while drained < connection::LINGER_LIMIT {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match timed(timeout, buf_reader.read(&mut buffer)).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => drained += n,
+            }
+        }
+    }
+}
+
+// This function reads the PROXY protocol header off the start of a connection, the same way
+// `proxy_protocol::read_header` does
+async fn read_proxy_header<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<proxy_protocol::ProxyHeader>, error::ProxyProtocolError> {
+    let mut received = Vec::new();
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            if received.is_empty() {
+                return Ok(None);
+            }
+            return Err(error::ProxyProtocolError::InvalidHeaderError(
+                "the connection was closed in the middle of the header".to_string(),
+            ));
+        }
+        let length = available.len();
+        received.extend_from_slice(available);
+        match proxy_protocol::parse(&received)? {
+            Some((header, consumed)) => {
+                reader.consume(consumed - (received.len() - length));
+                return Ok(header);
+            }
+            None => reader.consume(length),
+        };
+    }
+}
+
+// This function reads a single line off the reader and appends it to `buf`, line terminator
+// included, returning the length of the line without its terminator or `None` at EOF. Like
+// `utils::read_line` it fails with `too_long` when the line is longer than `limit` bytes
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    limit: usize,
+    too_long: error::RequestError,
+) -> Result<Option<usize>, error::WebServerError> {
+    let start = buf.len();
+    let n = (&mut *reader)
+        .take(limit as u64 + 1)
+        .read_until(b'\n', buf)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    let line = &buf[start..];
+    if line.len() > limit || (line.len() == limit && line.last() != Some(&b'\n')) {
+        return Err(error::WebServerError::RequestParseError(too_long));
+    }
+    let terminator = line.iter().rev().take_while(|&&b| b == b'\r' || b == b'\n');
+    return Ok(Some(line.len() - terminator.count()));
+}
+
+// The tokio connections the `AsyncWebServer` serves, they are turned back into blocking ones for
+// a handler taking them over, see `response::Upgrade`
+trait AsyncStream {
+    fn peer_addr(&self) -> io::Result<connection::SocketAddr>;
+    fn local_addr(&self) -> io::Result<connection::SocketAddr>;
+    fn into_stream(self) -> io::Result<connection::Stream>;
+}
+
+impl AsyncStream for net::TcpStream {
+    fn peer_addr(&self) -> io::Result<connection::SocketAddr> {
+        return net::TcpStream::peer_addr(self).map(connection::SocketAddr::Tcp);
+    }
+
+    fn local_addr(&self) -> io::Result<connection::SocketAddr> {
+        return net::TcpStream::local_addr(self).map(connection::SocketAddr::Tcp);
+    }
+
+    fn into_stream(self) -> io::Result<connection::Stream> {
+        let stream = self.into_std()?;
+        stream.set_nonblocking(false)?;
+        return Ok(connection::Stream::Tcp(stream));
+    }
+}
+
+#[cfg(unix)]
+impl AsyncStream for net::UnixStream {
+    fn peer_addr(&self) -> io::Result<connection::SocketAddr> {
+        return net::UnixStream::peer_addr(self).map(|address| {
+            connection::SocketAddr::Unix(address.as_pathname().map(std::path::PathBuf::from))
+        });
+    }
+
+    fn local_addr(&self) -> io::Result<connection::SocketAddr> {
+        return net::UnixStream::local_addr(self).map(|address| {
+            connection::SocketAddr::Unix(address.as_pathname().map(std::path::PathBuf::from))
+        });
+    }
+
+    fn into_stream(self) -> io::Result<connection::Stream> {
+        let stream = self.into_std()?;
+        stream.set_nonblocking(false)?;
+        return Ok(connection::Stream::Unix(stream));
+    }
+}
+
+// runs `future` for at most `timeout`, running out of time fails with `io::ErrorKind::TimedOut`
+async fn timed<T, E, F>(timeout: Duration, future: F) -> Result<T, E>
+where
+    E: From<io::Error>,
+    F: Future<Output = Result<T, E>>,
+{
+    match time::timeout(timeout, future).await {
+        Ok(result) => return result,
+        Err(_) => {
+            return Err(E::from(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "operation timed out",
+            )));
+        }
+    }
+}
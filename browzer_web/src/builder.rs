@@ -0,0 +1,411 @@
+use crate::{config, connection, error, router, shutdown, stats, utils, WebServer};
+use std::{net::TcpListener, path::PathBuf, sync::Arc, thread, time::Duration};
+
+#[cfg(unix)]
+use std::{
+    fs, io,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+// what the server listens on
+#[derive(Debug, Clone)]
+enum BindAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    // a socket which is already listening, e.g. one inherited from a parent process
+    Listener(Arc<connection::Listener>),
+    // the socket passed by systemd socket activation
+    #[cfg(unix)]
+    Systemd,
+}
+
+// A builder for `WebServer` which reports problems (e.g. the address already being in use) as
+// errors from `build` instead of panicking like `WebServer::new`, get one with
+// `WebServer::builder`
+#[derive(Debug, Clone)]
+pub struct WebServerBuilder {
+    addresses: Vec<BindAddress>,
+    unix_socket_permissions: Option<u32>,
+    remove_stale_socket: bool,
+    workers: usize,
+    queue_size: Option<usize>,
+    hide_banner: bool,
+    config: config::ServerConfig,
+    trusted_proxies: Vec<String>,
+    #[cfg(feature = "tls")]
+    tls_files: Option<(PathBuf, PathBuf)>,
+}
+
+impl Default for WebServerBuilder {
+    fn default() -> Self {
+        return WebServerBuilder {
+            addresses: Vec::new(),
+            unix_socket_permissions: None,
+            remove_stale_socket: true,
+            workers: match thread::available_parallelism() {
+                Ok(workers) => workers.get(),
+                Err(_) => 4,
+            },
+            queue_size: Some(1024),
+            hide_banner: false,
+            config: config::ServerConfig::default(),
+            trusted_proxies: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls_files: None,
+        };
+    }
+}
+
+impl WebServerBuilder {
+    pub fn new() -> WebServerBuilder {
+        return WebServerBuilder::default();
+    }
+
+    // an address to listen on, e.g. `0.0.0.0:8080`. It can be called several times (and mixed
+    // with the other ways of listening below) to accept connections on all of the addresses
+    pub fn bind(mut self, address: &str) -> WebServerBuilder {
+        self.addresses.push(BindAddress::Tcp(address.to_string()));
+        self
+    }
+
+    // listen on a Unix domain socket created at `path`
+    #[cfg(unix)]
+    pub fn bind_unix<P: Into<PathBuf>>(mut self, path: P) -> WebServerBuilder {
+        self.addresses.push(BindAddress::Unix(path.into()));
+        self
+    }
+
+    // accept connections on a socket which is already listening, e.g. one inherited from a parent
+    // process. Takes a `TcpListener` or a `UnixListener`
+    pub fn listener<L: Into<connection::Listener>>(mut self, listener: L) -> WebServerBuilder {
+        self.addresses
+            .push(BindAddress::Listener(Arc::new(listener.into())));
+        self
+    }
+
+    // accept connections on the sockets passed by systemd socket activation (`LISTEN_FDS` and
+    // `LISTEN_PID`), which lets systemd hold on to them while the server restarts. The sockets
+    // are taken over when the server is built, which fails if none were passed
+    #[cfg(unix)]
+    pub fn systemd_socket(mut self) -> WebServerBuilder {
+        self.addresses.push(BindAddress::Systemd);
+        self
+    }
+
+    // the file permissions (e.g. `0o660`) the Unix domain socket is created with, by default the
+    // socket gets the permissions allowed by the process umask
+    #[cfg(unix)]
+    pub fn unix_socket_permissions(mut self, mode: u32) -> WebServerBuilder {
+        self.unix_socket_permissions = Some(mode);
+        self
+    }
+
+    // whether a socket file left behind at the Unix domain socket path by a previous run which
+    // did not exit cleanly is removed before binding, a socket which still has a server accepting
+    // connections on it is never removed. Defaults to `true`
+    #[cfg(unix)]
+    pub fn remove_stale_socket(mut self, remove_stale_socket: bool) -> WebServerBuilder {
+        self.remove_stale_socket = remove_stale_socket;
+        self
+    }
+
+    // the number of worker threads serving connections, defaults to the available parallelism
+    pub fn workers(mut self, workers: usize) -> WebServerBuilder {
+        self.workers = workers;
+        self
+    }
+
+    // maximum number of accepted connections waiting for a free worker thread, `None` lets the
+    // queue grow without bound
+    pub fn queue_size(mut self, queue_size: Option<usize>) -> WebServerBuilder {
+        self.queue_size = queue_size;
+        self
+    }
+
+    // serve connections from `threads` event loop threads instead of tying a worker thread up with
+    // every connection, see `ServerConfig::io_threads`
+    pub fn io_threads(mut self, threads: usize) -> WebServerBuilder {
+        self.config.io_threads = threads;
+        self
+    }
+
+    pub fn overload_policy(mut self, policy: config::OverloadPolicy) -> WebServerBuilder {
+        self.config.overload_policy = policy;
+        self
+    }
+
+    // expect a PROXY protocol header from a load balancer at the start of every connection, see
+    // `ServerConfig::proxy_protocol`. The header is required: a connection without one is closed
+    // before any request is read from it, so only turn this on when every client goes through the
+    // load balancer. Defaults to `false`
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> WebServerBuilder {
+        self.config.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    // trust the proxies in `cidr` (e.g. `10.0.0.0/8` or a single address) to tell the address of
+    // the client a request is forwarded for, see `Request::client_ip`. It can be called several
+    // times, the ranges are checked when the server is built
+    pub fn trusted_proxy(mut self, cidr: &str) -> WebServerBuilder {
+        self.trusted_proxies.push(cidr.to_string());
+        self
+    }
+
+    pub fn hide_banner(mut self, hide_banner: bool) -> WebServerBuilder {
+        self.hide_banner = hide_banner;
+        self
+    }
+
+    // replaces all the connection settings at once, the other setters below change single fields
+    // of it
+    pub fn config(mut self, config: config::ServerConfig) -> WebServerBuilder {
+        self.config = config;
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: bool) -> WebServerBuilder {
+        self.config.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> WebServerBuilder {
+        self.config.keep_alive_timeout = timeout;
+        self
+    }
+
+    pub fn max_requests_per_connection(mut self, max_requests: usize) -> WebServerBuilder {
+        self.config.max_requests_per_connection = max_requests;
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> WebServerBuilder {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn header_read_timeout(mut self, timeout: Duration) -> WebServerBuilder {
+        self.config.header_read_timeout = timeout;
+        self
+    }
+
+    pub fn body_read_timeout(mut self, timeout: Duration) -> WebServerBuilder {
+        self.config.body_read_timeout = timeout;
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> WebServerBuilder {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    // minimum rate in bytes per second at which clients have to send request bodies, `None`
+    // turns the check off
+    pub fn min_transfer_rate(mut self, bytes_per_second: Option<u64>) -> WebServerBuilder {
+        self.config.min_transfer_rate = bytes_per_second;
+        self
+    }
+
+    pub fn max_request_line_length(mut self, length: usize) -> WebServerBuilder {
+        self.config.limits.max_request_line_length = length;
+        self
+    }
+
+    pub fn max_header_count(mut self, count: usize) -> WebServerBuilder {
+        self.config.limits.max_header_count = count;
+        self
+    }
+
+    pub fn max_header_size(mut self, size: usize) -> WebServerBuilder {
+        self.config.limits.max_header_size = size;
+        self
+    }
+
+    pub fn max_body_size(mut self, size: usize) -> WebServerBuilder {
+        self.config.limits.max_body_size = size;
+        self
+    }
+
+    // serve over TLS using the PEM encoded certificate chain and private key, the files are read
+    // when the server is built
+    #[cfg(feature = "tls")]
+    pub fn tls<P: Into<PathBuf>>(mut self, cert_chain_path: P, key_path: P) -> WebServerBuilder {
+        self.tls_files = Some((cert_chain_path.into(), key_path.into()));
+        self
+    }
+
+    // This function validates the settings, binds the listeners and starts the worker threads
+    pub fn build(self) -> Result<WebServer, error::WebServerError> {
+        if self.addresses.is_empty() {
+            return Err(error::WebServerError::ConfigError(
+                "no address to bind to, set one with `bind`".to_string(),
+            ));
+        }
+        if self.workers == 0 {
+            return Err(error::WebServerError::ConfigError(
+                "the server needs at least one worker thread".to_string(),
+            ));
+        }
+
+        #[allow(unused_mut)]
+        let mut config = self.config;
+        #[cfg(feature = "tls")]
+        if let Some((cert_chain_path, key_path)) = self.tls_files {
+            config.tls = Some(crate::tls::TlsAcceptor::from_pem_files(
+                cert_chain_path,
+                key_path,
+            )?);
+        }
+        for cidr in &self.trusted_proxies {
+            config.trusted_proxies.push(cidr.parse()?);
+        }
+        config.validate()?;
+
+        let mut listeners = Vec::new();
+        let mut addresses = Vec::new();
+        #[cfg(unix)]
+        let mut unix_socket_paths = Vec::new();
+        for address in self.addresses {
+            match address {
+                BindAddress::Tcp(address) => match TcpListener::bind(&address) {
+                    Ok(listener) => {
+                        listeners.push(connection::Listener::Tcp(listener));
+                        addresses.push(address);
+                    }
+                    Err(e) => return Err(error::WebServerError::BindError(address, e)),
+                },
+                #[cfg(unix)]
+                BindAddress::Unix(path) => {
+                    #[cfg(feature = "tls")]
+                    if config.tls.is_some() {
+                        return Err(error::WebServerError::ConfigError(
+                            "TLS is only supported over TCP".to_string(),
+                        ));
+                    }
+                    let listener = match WebServerBuilder::bind_unix_socket(
+                        &path,
+                        self.remove_stale_socket,
+                        self.unix_socket_permissions,
+                    ) {
+                        Ok(listener) => listener,
+                        Err(e) => {
+                            return Err(error::WebServerError::BindError(
+                                format!("unix:{}", path.display()),
+                                e,
+                            ));
+                        }
+                    };
+                    listeners.push(connection::Listener::Unix(listener));
+                    addresses.push(format!("unix:{}", path.display()));
+                    unix_socket_paths.push(path);
+                }
+                BindAddress::Listener(listener) => {
+                    let listener = match Arc::try_unwrap(listener) {
+                        Ok(listener) => listener,
+                        Err(listener) => listener.try_clone()?,
+                    };
+                    let (listener, address) = WebServerBuilder::adopt_listener(listener, &config)?;
+                    listeners.push(listener);
+                    addresses.push(address);
+                }
+                #[cfg(unix)]
+                BindAddress::Systemd => {
+                    let systemd_listeners = match connection::Listener::from_systemd() {
+                        Ok(listeners) => listeners,
+                        Err(e) => {
+                            return Err(error::WebServerError::BindError(
+                                "the systemd sockets".to_string(),
+                                e,
+                            ));
+                        }
+                    };
+                    for listener in systemd_listeners {
+                        let (listener, address) =
+                            WebServerBuilder::adopt_listener(listener, &config)?;
+                        listeners.push(listener);
+                        addresses.push(address);
+                    }
+                }
+            };
+        }
+
+        return Ok(WebServer {
+            listeners,
+            request_pool: Arc::new(utils::thread_pool::ThreadPool::with_queue_size(
+                self.workers,
+                self.queue_size,
+            )),
+            hide_banner: self.hide_banner,
+            addresses,
+            #[cfg(unix)]
+            unix_socket_paths,
+            config,
+            router: Arc::new(router::WebRouter::new()),
+            shutdown: Arc::new(shutdown::ShutdownState::default()),
+            counters: Arc::new(stats::ServerCounters::default()),
+        });
+    }
+
+    // This function prepares a socket which was bound outside of the builder to be served, the
+    // accept loop expects it to block
+    fn adopt_listener(
+        listener: connection::Listener,
+        config: &config::ServerConfig,
+    ) -> Result<(connection::Listener, String), error::WebServerError> {
+        if config.tls_enabled() && !matches!(listener, connection::Listener::Tcp(_)) {
+            return Err(error::WebServerError::ConfigError(
+                "TLS is only supported over TCP".to_string(),
+            ));
+        }
+        listener.set_nonblocking(false)?;
+        let address = listener.local_addr()?.to_string();
+        return Ok((listener, address));
+    }
+
+    // This function creates the Unix domain socket at `path`, a stale socket file is removed first
+    // if `remove_stale_socket` is set. A socket is considered stale if nothing accepts connections
+    // on it anymore, anything at `path` which is not a socket is left alone.
+    #[cfg(unix)]
+    fn bind_unix_socket(
+        path: &Path,
+        remove_stale_socket: bool,
+        permissions: Option<u32>,
+    ) -> io::Result<UnixListener> {
+        if remove_stale_socket {
+            if let Ok(metadata) = fs::symlink_metadata(path) {
+                if metadata.file_type().is_socket() {
+                    match UnixStream::connect(path) {
+                        Ok(_) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::AddrInUse,
+                                "another server is accepting connections on this socket",
+                            ));
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                            fs::remove_file(path)?;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+
+        // the socket is created with `permissions` right away, setting them once it is bound would
+        // leave a moment in which anyone allowed by the umask could connect. The umask is shared by
+        // the whole process, it is only changed for as long as `bind` takes
+        let listener = match permissions {
+            Some(mode) => {
+                let umask = unsafe { libc::umask(!mode as libc::mode_t & 0o777) };
+                let listener = UnixListener::bind(path);
+                unsafe { libc::umask(umask) };
+                listener?
+            }
+            None => UnixListener::bind(path)?,
+        };
+        return Ok(listener);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::error;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    time::Duration,
+};
+
+// This struct holds the connection level settings of the `WebServer`, it is cloned into an `Arc`
+// when the server starts listening so that every worker thread handling a connection can read the
+// same settings without owning the `WebServer` itself
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // whether persistent (keep-alive) connections are allowed at all, if this is `false` every
+    // connection is closed after its first response
+    pub keep_alive: bool,
+    // how long an idle persistent connection is kept open while waiting for the next request
+    pub keep_alive_timeout: Duration,
+    // maximum number of requests served over a single connection before it is closed, `0` means
+    // there is no limit
+    pub max_requests_per_connection: usize,
+    // how long a graceful shutdown waits for the requests in flight to finish before giving up on
+    // them and returning from `WebServer::listen` anyway
+    pub shutdown_timeout: Duration,
+    // how long a client has to send the complete head (request line and headers) of a request,
+    // counted from the first byte of the request (or from the start of the TLS handshake)
+    pub header_read_timeout: Duration,
+    // how long reading the body of a request may wait for the client to send more data
+    pub body_read_timeout: Duration,
+    // how long writing a response may wait for the client to take in more data
+    pub write_timeout: Duration,
+    // minimum average rate, in bytes per second, at which a client has to send the body of a
+    // request once the transfer has been running for 5 seconds, `None` turns the check off
+    pub min_transfer_rate: Option<u64>,
+    // number of threads reading requests off connections and writing responses to them with
+    // non-blocking I/O, handing only complete requests to the worker threads. `0` turns the event
+    // loop off, every connection is then served by a worker thread of its own from start to end
+    pub io_threads: usize,
+    // bounds on the size of incoming requests
+    pub limits: RequestLimits,
+    // what the server does with a new connection while every worker thread is busy and the
+    // connection queue is full
+    pub overload_policy: OverloadPolicy,
+    // whether every connection starts with a PROXY protocol (version 1 or 2) header, sent by a
+    // load balancer in front of the server to relay the addresses of the client, see
+    // `Request::proxy`. Connections without a valid header are closed, so it may only be turned on
+    // if the server can not be reached other than through the load balancer
+    pub proxy_protocol: bool,
+    // the proxies (e.g. load balancers) trusted to tell the address of the client a request is
+    // forwarded for in the `Forwarded` or `X-Forwarded-For` header, see `Request::client_ip`.
+    // Connections over a Unix domain socket are always trusted, only local processes the socket's
+    // permissions allow can connect to it
+    pub trusted_proxies: Vec<IpCidr>,
+    // when set, every accepted connection is encrypted with TLS before any request is read from it
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsAcceptor>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        return ServerConfig {
+            keep_alive: true,
+            keep_alive_timeout: Duration::from_secs(5),
+            max_requests_per_connection: 100,
+            shutdown_timeout: Duration::from_secs(30),
+            header_read_timeout: Duration::from_secs(10),
+            body_read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            min_transfer_rate: Some(240),
+            io_threads: 0,
+            limits: RequestLimits::default(),
+            overload_policy: OverloadPolicy::Block,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        };
+    }
+}
+
+impl ServerConfig {
+    // This function returns whether connections are encrypted with TLS
+    pub fn tls_enabled(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        return false;
+    }
+
+    // This function checks that the settings can be used together, the I/O threads can not do
+    // TLS and would answer the connections in plaintext
+    pub(crate) fn validate(&self) -> Result<(), error::WebServerError> {
+        if self.io_threads > 0 && self.tls_enabled() {
+            return Err(error::WebServerError::ConfigError(
+                "TLS connections can not be served by the event loop, set `io_threads` to 0"
+                    .to_string(),
+            ));
+        }
+        return Ok(());
+    }
+}
+
+// The largest request the server is willing to read, a request going over any of these limits is
+// rejected before the memory for it is allocated
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    // maximum length of the request line in bytes, longer ones are answered with `414 URI Too Long`
+    pub max_request_line_length: usize,
+    // maximum number of header fields, more are answered with
+    // `431 Request Header Fields Too Large`
+    pub max_header_count: usize,
+    // maximum size of all the header fields together in bytes, larger heads are answered with
+    // `431 Request Header Fields Too Large`
+    pub max_header_size: usize,
+    // maximum size of the body in bytes (after decoding chunked encoding), larger bodies are
+    // answered with `413 Payload Too Large`
+    pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        return RequestLimits {
+            max_request_line_length: 8 * 1024,
+            max_header_count: 100,
+            max_header_size: 16 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+        };
+    }
+}
+
+// How the `WebServer` handles connections arriving faster than its worker threads can serve them,
+// once the connection queue is full
+#[derive(Debug, Clone, PartialEq)]
+pub enum OverloadPolicy {
+    // stop accepting connections until a worker thread frees up a spot in the queue, new clients
+    // wait in the backlog of the listening socket
+    Block,
+    // answer new connections right away with `503 Service Unavailable`, telling the client to try
+    // again after `retry_after`, and close them
+    Reject { retry_after: Duration },
+}
+
+// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`. A single
+// address (e.g. `192.0.2.1`) is a range of its own. Get one with `IpCidr::new` or by parsing it,
+// both of which make sure the prefix length fits the address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpCidr {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl IpCidr {
+    // This function creates the range of the addresses which share their first `prefix_length`
+    // bits with `address`, it fails if the prefix is longer than the address (32 bits for IPv4 and
+    // 128 bits for IPv6). An IPv4 address mapped to IPv6 is taken as the IPv4 address, so its
+    // prefix length counts the bits of the IPv4 address
+    pub fn new(address: IpAddr, prefix_length: u8) -> Result<IpCidr, error::WebServerError> {
+        let address = address.to_canonical();
+        let max_prefix_length = match address {
+            IpAddr::V4(_) => Ipv4Addr::BITS as u8,
+            IpAddr::V6(_) => Ipv6Addr::BITS as u8,
+        };
+        if prefix_length > max_prefix_length {
+            return Err(error::WebServerError::ConfigError(format!(
+                "invalid CIDR: {}/{}, the prefix length of the address is at most {}",
+                address, prefix_length, max_prefix_length
+            )));
+        }
+        return Ok(IpCidr {
+            address,
+            prefix_length,
+        });
+    }
+
+    pub fn address(&self) -> IpAddr {
+        return self.address;
+    }
+
+    pub fn prefix_length(&self) -> u8 {
+        return self.prefix_length;
+    }
+
+    // This function returns whether `address` is in the range, IPv4 addresses mapped to IPv6
+    // (e.g. `::ffff:192.0.2.1`, as dual-stack sockets report them) count as IPv4 addresses
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address.to_canonical(), address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                return u32::from(network) & mask == u32::from(address) & mask;
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                return u128::from(network) & mask == u128::from(address) & mask;
+            }
+            _ => return false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = error::WebServerError;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let invalid = || error::WebServerError::ConfigError(format!("invalid CIDR: {}", cidr));
+        let (address, prefix_length) = match cidr.trim().split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (cidr.trim(), None),
+        };
+        let address = match address.parse::<IpAddr>() {
+            Ok(address) => address.to_canonical(),
+            Err(_) => return Err(invalid()),
+        };
+        let prefix_length = match prefix_length.map(|length| length.parse::<u8>()) {
+            Some(Ok(length)) => length,
+            Some(Err(_)) => return Err(invalid()),
+            None => match address {
+                IpAddr::V4(_) => Ipv4Addr::BITS as u8,
+                IpAddr::V6(_) => Ipv6Addr::BITS as u8,
+            },
+        };
+        return IpCidr::new(address, prefix_length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> IpAddr {
+        return address.parse().unwrap();
+    }
+
+    #[test]
+    fn rejects_a_prefix_longer_than_the_address() {
+        assert!(IpCidr::new(address("10.0.0.0"), 32).is_ok());
+        assert!(IpCidr::new(address("10.0.0.0"), 33).is_err());
+        assert!(IpCidr::new(address("2001:db8::"), 128).is_ok());
+        assert!(IpCidr::new(address("2001:db8::"), 129).is_err());
+        assert!(IpCidr::new(address("2001:db8::"), u8::MAX).is_err());
+        // a mapped IPv4 address counts the bits of the IPv4 address
+        assert!(IpCidr::new(address("::ffff:10.0.0.0"), 104).is_err());
+        assert_eq!(
+            IpCidr::new(address("::ffff:10.0.0.0"), 8).unwrap(),
+            "10.0.0.0/8".parse::<IpCidr>().unwrap()
+        );
+        for cidr in ["10.0.0.0/33", "2001:db8::/129", "10.0.0.0/-1", "10.0.0.0/"] {
+            assert!(cidr.parse::<IpCidr>().is_err(), "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn contains_the_addresses_sharing_the_prefix() {
+        let cidr: IpCidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains(&address("10.1.255.255")));
+        assert!(cidr.contains(&address("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(&address("10.2.0.0")));
+        assert!(!cidr.contains(&address("2001:db8::1")));
+
+        let cidr: IpCidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(&address("2001:db8:ffff::1")));
+        assert!(!cidr.contains(&address("2001:db9::1")));
+
+        // a prefix of 0 takes in every address, a full one only the address itself
+        assert!(IpCidr::new(address("0.0.0.0"), 0)
+            .unwrap()
+            .contains(&address("192.0.2.1")));
+        let single: IpCidr = "192.0.2.1".parse().unwrap();
+        assert_eq!(single.prefix_length(), 32);
+        assert!(single.contains(&address("192.0.2.1")));
+        assert!(!single.contains(&address("192.0.2.2")));
+    }
+}
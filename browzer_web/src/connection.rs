@@ -0,0 +1,670 @@
+use crate::shutdown;
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{self, TcpListener, TcpStream},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::{
+    env, fs, mem,
+    os::unix::{
+        fs::FileTypeExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// the first file descriptor systemd passes listening sockets on, see `sd_listen_fds(3)`
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+// whether the sockets passed by systemd were taken over already, see `Listener::from_systemd`
+#[cfg(unix)]
+static SYSTEMD_SOCKETS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+// A socket the `WebServer` accepts connections on
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    // This function blocks until a new connection arrives and returns it
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => match listener.accept() {
+                Ok((stream, _)) => Ok(Stream::Tcp(stream)),
+                Err(e) => Err(e),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener.accept() {
+                Ok((stream, _)) => Ok(Stream::Unix(stream)),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Listener> {
+        match self {
+            Listener::Tcp(listener) => listener.try_clone().map(Listener::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.try_clone().map(Listener::Unix),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    // This function takes over the listening sockets systemd passed to the process through socket
+    // activation (`LISTEN_FDS` and `LISTEN_PID`), in the order they are listed in the socket
+    // unit. Every passed descriptor has to be a listening TCP or Unix domain socket, otherwise all
+    // of them are closed and an error is returned. The sockets can only be taken over once, the
+    // variables are left in the environment (changing it is not safe once other threads may be
+    // running), a child process does not take them for its own as `LISTEN_PID` names another one
+    #[cfg(unix)]
+    pub fn from_systemd() -> io::Result<Vec<Listener>> {
+        let listen_pid = match env::var("LISTEN_PID") {
+            Ok(listen_pid) => listen_pid,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no sockets were passed by systemd, LISTEN_PID is not set",
+                ));
+            }
+        };
+        let listen_fds = env::var("LISTEN_FDS").unwrap_or_default();
+        match listen_pid.trim().parse::<u32>() {
+            Ok(pid) if pid == std::process::id() => {}
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "the sockets passed by systemd are meant for another process",
+                ));
+            }
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid LISTEN_PID: {}", listen_pid),
+                ));
+            }
+        };
+        let count = match listen_fds.trim().parse::<RawFd>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid LISTEN_FDS: {}", listen_fds),
+                ));
+            }
+        };
+        if SYSTEMD_SOCKETS_TAKEN.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the sockets passed by systemd were already taken over",
+            ));
+        }
+
+        // the descriptors are all checked before any of them is taken over
+        let fds = SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count;
+        let mut families = Vec::new();
+        for fd in fds.clone() {
+            match listening_socket_family(fd) {
+                Ok(family) => families.push(family),
+                Err(e) => {
+                    for fd in fds {
+                        unsafe { libc::close(fd) };
+                    }
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("file descriptor {} passed by systemd: {}", fd, e),
+                    ));
+                }
+            };
+        }
+
+        // systemd hands the ownership of the descriptors to the process, from here on any of
+        // them failing drops (and closes) all of them
+        let listeners: Vec<Listener> = fds
+            .zip(families)
+            .map(|(fd, family)| match family {
+                libc::AF_UNIX => Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) }),
+                _ => Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) }),
+            })
+            .collect();
+        for listener in &listeners {
+            // the inherited descriptors are not closed on exec, a child process would hold on to
+            // them
+            let fd = match listener {
+                Listener::Tcp(listener) => listener.as_raw_fd(),
+                Listener::Unix(listener) => listener.as_raw_fd(),
+            };
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+            if flags == -1
+                || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+            listener.set_nonblocking(false)?;
+        }
+        return Ok(listeners);
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(SocketAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .local_addr()
+                .map(|address| SocketAddr::Unix(address.as_pathname().map(PathBuf::from))),
+        }
+    }
+}
+
+// This function removes the Unix domain sockets the builder created at `paths` once the server
+// stopped accepting connections on them, whatever took the place of a socket is left alone
+#[cfg(unix)]
+pub(crate) fn remove_unix_sockets(paths: &[PathBuf]) {
+    for path in paths {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => match fs::remove_file(path) {
+                Ok(_) => {}
+                Err(e) => eprintln!(
+                    "Failed to remove the socket at {}, Error: {}",
+                    path.display(),
+                    e
+                ),
+            },
+            _ => {}
+        }
+    }
+}
+
+// This function returns the address family of `fd` if it is a listening TCP or Unix domain socket
+#[cfg(unix)]
+fn listening_socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    let socket_option = |option: libc::c_int| -> io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        match unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut length,
+            )
+        } {
+            -1 => return Err(io::Error::last_os_error()),
+            _ => return Ok(value),
+        }
+    };
+    if socket_option(libc::SO_TYPE)? != libc::SOCK_STREAM
+        || socket_option(libc::SO_ACCEPTCONN)? == 0
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a listening stream socket",
+        ));
+    }
+
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut length = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockname(
+            fd,
+            &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut length,
+        )
+    } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    match address.ss_family as libc::c_int {
+        family @ (libc::AF_INET | libc::AF_INET6 | libc::AF_UNIX) => return Ok(family),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a TCP or Unix domain socket",
+            ));
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+// A connection accepted by the `WebServer`, requests are parsed from and responses written to it
+// no matter which transport it runs over
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<crate::tls::TlsStream>),
+}
+
+impl Stream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.socket().set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.socket().set_write_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.socket().shutdown(how),
+        }
+    }
+
+    // This function returns the address of the server's end of the connection, e.g. which of the
+    // addresses the server listens on the client connected to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.local_addr().map(SocketAddr::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream
+                .local_addr()
+                .map(|address| SocketAddr::Unix(address.as_pathname().map(PathBuf::from))),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.socket().local_addr().map(SocketAddr::Tcp),
+        }
+    }
+
+    // This function returns the address of the client's end of the connection
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().map(SocketAddr::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream
+                .peer_addr()
+                .map(|address| SocketAddr::Unix(address.as_pathname().map(PathBuf::from))),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.socket().peer_addr().map(SocketAddr::Tcp),
+        }
+    }
+
+    // This function returns the details of the TLS session if the stream is encrypted
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Some(stream.session_info()),
+            _ => None,
+        }
+    }
+
+    // This function gets a connection ready to be closed after a response which was sent before
+    // the whole request was read. Closing a socket with unread data in it resets the connection,
+    // which can make the client lose the response, so the sending half is shut down first and
+    // what the client still sends is read and thrown away, up to `limit` bytes and for no longer
+    // than `timeout` in total
+    pub(crate) fn linger(&mut self, limit: usize, timeout: Duration) {
+        #[cfg(feature = "tls")]
+        if let Stream::Tls(stream) = self {
+            stream.close_notify();
+        }
+        let _ = self.shutdown(net::Shutdown::Write);
+        let deadline = Instant::now() + timeout;
+        let mut buffer = [0; 4096];
+        let mut drained = 0;
+        while drained < limit {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() || self.set_read_timeout(Some(timeout)).is_err() {
+                break;
+            }
+            match self.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => drained += n,
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// how long a body transfer may run before `min_transfer_rate` is enforced, so that a slow start
+// (e.g. TCP slow start or a client computing the body) is not punished
+const MIN_TRANSFER_RATE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// how much output a `TimedStream` holds back before it writes it out anyway
+const MAX_BUFFERED_OUTPUT: usize = 64 * 1024;
+
+// how long a connection closed after an error response is given to stop sending, and how much of
+// what it sends is thrown away in the meantime, see `Stream::linger`
+pub(crate) const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+pub(crate) const LINGER_LIMIT: usize = 1024 * 1024;
+
+// This function returns whether a transfer which moved `transferred` bytes in `elapsed` is slower
+// than `min_transfer_rate` bytes per second, not counting the grace period at its start
+pub(crate) fn below_min_transfer_rate(
+    min_transfer_rate: u64,
+    transferred: u64,
+    elapsed: Duration,
+) -> bool {
+    return elapsed > MIN_TRANSFER_RATE_GRACE_PERIOD
+        && (transferred as f64)
+            < min_transfer_rate as f64 * (elapsed - MIN_TRANSFER_RATE_GRACE_PERIOD).as_secs_f64();
+}
+
+// A `Stream` whose reads are bounded in time, it protects the worker threads from clients which
+// send their requests slowly (or not at all) to tie them up. Reads can be bounded by
+//
+// - a deadline by which the current read phase (e.g. the request head) has to be over
+// - an idle timeout, the longest time a single read may wait for data
+// - a minimum average transfer rate over the current read phase
+//
+// a read which runs into any of these limits fails with `io::ErrorKind::TimedOut`.
+//
+// Writes are held back until the stream is flushed or has to wait for input. A response's head
+// and body thus go out together, and so do the responses to pipelined requests that were already
+// buffered, instead of being held up by Nagle's algorithm waiting for the client to acknowledge
+// what was sent before
+#[derive(Debug)]
+pub struct TimedStream {
+    stream: Stream,
+    output: Vec<u8>,
+    deadline: Option<Instant>,
+    idle_timeout: Option<Duration>,
+    min_transfer_rate: Option<u64>,
+    transfer_start: Instant,
+    transferred: u64,
+    // the read timeout currently set on the socket, to avoid setting it again on every read
+    socket_timeout: Option<Duration>,
+}
+
+impl TimedStream {
+    pub fn new(stream: Stream) -> TimedStream {
+        return TimedStream {
+            stream,
+            output: Vec::new(),
+            deadline: None,
+            idle_timeout: None,
+            min_transfer_rate: None,
+            transfer_start: Instant::now(),
+            transferred: 0,
+            socket_timeout: None,
+        };
+    }
+
+    pub fn get_ref(&self) -> &Stream {
+        return &self.stream;
+    }
+
+    // This function starts a new read phase with the given limits, `None` turns a limit off.
+    // `min_transfer_rate` is in bytes per second and is only enforced once the phase has been
+    // running for a few seconds
+    pub fn set_limits(
+        &mut self,
+        deadline: Option<Instant>,
+        idle_timeout: Option<Duration>,
+        min_transfer_rate: Option<u64>,
+    ) {
+        self.deadline = deadline;
+        self.idle_timeout = idle_timeout;
+        self.min_transfer_rate = min_transfer_rate;
+        self.transfer_start = Instant::now();
+        self.transferred = 0;
+    }
+
+    // This function writes out the held back output and lingers on the connection before it is
+    // closed, see `Stream::linger`. The stream is not to be read from afterwards
+    pub(crate) fn linger(&mut self) {
+        let _ = self.flush();
+        self.stream.linger(LINGER_LIMIT, LINGER_TIMEOUT);
+    }
+
+    fn timed_out(message: &str) -> io::Error {
+        return io::Error::new(io::ErrorKind::TimedOut, message.to_string());
+    }
+
+    fn write_output(&mut self) -> io::Result<()> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        let result = self.stream.write_all(&self.output);
+        self.output.clear();
+        return result;
+    }
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // the client may be waiting for what was written before it sends anything else
+        if !self.output.is_empty() {
+            self.flush()?;
+        }
+
+        let now = Instant::now();
+        let mut timeout = self.idle_timeout;
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(now);
+            if remaining.is_zero() {
+                return Err(TimedStream::timed_out("read deadline exceeded"));
+            }
+            timeout = Some(match timeout {
+                Some(timeout) => timeout.min(remaining),
+                None => remaining,
+            });
+        }
+        if let Some(min_transfer_rate) = self.min_transfer_rate {
+            let elapsed = now.duration_since(self.transfer_start);
+            if below_min_transfer_rate(min_transfer_rate, self.transferred, elapsed) {
+                return Err(TimedStream::timed_out("transfer rate below the minimum"));
+            }
+        }
+
+        if timeout != self.socket_timeout {
+            self.stream.set_read_timeout(timeout)?;
+            self.socket_timeout = timeout;
+        }
+        match self.stream.read(buf) {
+            Ok(n) => {
+                self.transferred += n as u64;
+                Ok(n)
+            }
+            // depending on the platform a socket read timeout is reported as either of these
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Err(TimedStream::timed_out("read timed out"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Write for TimedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.output.len() + buf.len() > MAX_BUFFERED_OUTPUT {
+            self.write_output()?;
+            if buf.len() > MAX_BUFFERED_OUTPUT {
+                return self.stream.write(buf);
+            }
+        }
+        self.output.extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_output()?;
+        return self.stream.flush();
+    }
+}
+
+impl Drop for TimedStream {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// A connection taken over from the `WebServer` after a `101 Switching Protocols` response so that
+// another protocol can be spoken on it, see `response::Upgrade`. Reading it first returns what the
+// client already sent past the request that asked for the upgrade.
+//
+// The connection is served on a worker thread (or a blocking thread of the tokio runtime) for as
+// long as it stays open, and counts as an active connection of the server until it is dropped
+#[derive(Debug)]
+pub struct Upgraded {
+    stream: TimedStream,
+    buffered: io::Cursor<Vec<u8>>,
+    // the size of the largest message a protocol spoken on the connection should accept, this is
+    // `RequestLimits::max_body_size`
+    pub max_message_size: usize,
+    shutdown: Arc<shutdown::ShutdownState>,
+}
+
+impl Upgraded {
+    pub(crate) fn new(
+        mut stream: TimedStream,
+        buffered: Vec<u8>,
+        max_message_size: usize,
+        shutdown: Arc<shutdown::ShutdownState>,
+    ) -> Upgraded {
+        stream.set_limits(None, None, None);
+        return Upgraded {
+            stream,
+            buffered: io::Cursor::new(buffered),
+            max_message_size,
+            shutdown,
+        };
+    }
+
+    pub fn get_ref(&self) -> &Stream {
+        return self.stream.get_ref();
+    }
+
+    // This function bounds how long a single read may wait for data, `None` waits forever
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.stream.set_limits(None, timeout, None);
+    }
+
+    // This function tells whether the server is shutting down, a long lived connection should be
+    // wound up then
+    pub fn is_shutting_down(&self) -> bool {
+        return self.shutdown.is_requested();
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.buffered.position() as usize) < self.buffered.get_ref().len() {
+            return self.buffered.read(buf);
+        }
+        return self.stream.read(buf);
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.stream.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.stream.flush();
+    }
+}
+
+// The address of one end of a connection, Unix domain sockets may be unnamed
+#[derive(Debug, Clone, PartialEq)]
+pub enum SocketAddr {
+    Tcp(net::SocketAddr),
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+impl fmt::Display for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocketAddr::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            SocketAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            SocketAddr::Unix(None) => write!(f, "unix:(unnamed)"),
+        }
+    }
+}
+
+// The parameters negotiated during the TLS handshake of an encrypted connection, available to
+// handlers through `Request::tls`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsInfo {
+    // e.g. `TLSv1_3`
+    pub protocol_version: String,
+    // e.g. `TLS13_AES_256_GCM_SHA384`
+    pub cipher_suite: String,
+    // the application protocol agreed on through ALPN, if the client offered any
+    pub alpn_protocol: Option<String>,
+    // the host name the client asked for through SNI, if it sent one
+    pub server_name: Option<String>,
+}
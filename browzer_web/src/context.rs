@@ -1,6 +1,6 @@
+use crate::{request, response, sse, utils};
 use serde_urlencoded;
-use crate::{request, response, utils};
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 #[derive(Debug)]
 pub struct Context {
@@ -27,10 +27,55 @@ impl Context {
     ) -> response::Response {
         let res = &mut self.response;
         res.status_code = status_code;
-        res.body = input.to_string();
+        res.body = response::Body::from(input);
         res.clone()
     }
 
+    // This function sends a response with a binary body, the bytes are written to the client
+    // exactly as they are
+    pub fn send_bytes(
+        &mut self,
+        status_code: utils::HttpStatusCode,
+        input: &[u8],
+    ) -> response::Response {
+        let res = &mut self.response;
+        res.status_code = status_code;
+        res.body = response::Body::from(input);
+        res.clone()
+    }
+
+    // This function sends a response whose body is streamed to the client with chunked encoding
+    // as `body` produces it, see `response::StreamBody` for the ways to create one
+    pub fn send_stream(
+        &mut self,
+        status_code: utils::HttpStatusCode,
+        body: response::StreamBody,
+    ) -> response::Response {
+        let res = &mut self.response;
+        res.status_code = status_code;
+        res.body = response::Body::Stream(body);
+        res.clone()
+    }
+
+    // This function sends a Server-Sent Events response (`text/event-stream`). Events are emitted
+    // through the returned `sse::EventSender`, by the handler itself or by other threads holding a
+    // clone of it, and the response lasts until every sender is dropped or the server starts
+    // shutting down. A heartbeat comment is sent whenever no event was sent for
+    // `heartbeat_interval` (one second at the least), so that a client which went away is noticed
+    // and the senders start failing
+    pub fn send_events(
+        &mut self,
+        heartbeat_interval: Duration,
+    ) -> (response::Response, sse::EventSender) {
+        let (response, sender) =
+            sse::event_stream(heartbeat_interval, self.request.shutdown.clone());
+        let res = &mut self.response;
+        res.status_code = response.status_code;
+        res.body = response.body;
+        res.headers.extend(response.headers);
+        (res.clone(), sender)
+    }
+
     pub fn redirect(
         &mut self,
         status_code: utils::HttpStatusCode,
@@ -48,11 +93,8 @@ impl Context {
             Some(content_type) => content_type,
             None => return String::from(""),
         };
-        match serde_urlencoded::from_str::<HashMap<String, String>>(match &self.request.body {
-            Some(body) => match std::str::from_utf8(body.trim().as_bytes()) {
-                Ok(body_str) => body_str.trim(),
-                Err(_) => return String::from(""),
-            },
+        match serde_urlencoded::from_str::<HashMap<String, String>>(match self.request.body_str() {
+            Some(body_str) => body_str.trim(),
             None => return String::from(""),
         }) {
             Ok(data) => {
@@ -1,9 +1,10 @@
-use thiserror::Error;
+use crate::utils;
 use std::{
     io,
     num::ParseIntError,
     sync::{self, mpsc},
 };
+use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum ThreadPoolError {
@@ -30,6 +31,54 @@ pub enum RequestError {
 
     #[error("Empty HTTP request")]
     EmptyRequestError,
+
+    #[error("Invalid Content-Length header: {0}")]
+    InvalidContentLengthError(String),
+
+    #[error("Invalid header field: {0}")]
+    InvalidHeaderError(String),
+
+    #[error("Request carries both Content-Length and Transfer-Encoding headers")]
+    ConflictingFramingError,
+
+    #[error("Unsupported Transfer-Encoding: {0}")]
+    UnsupportedTransferEncodingError(String),
+
+    #[error("Invalid chunked body: {0}")]
+    InvalidChunkedBodyError(String),
+
+    #[error("Request line is longer than {0} bytes")]
+    RequestLineTooLongError(usize),
+
+    #[error("Request header fields are larger than allowed: {0}")]
+    HeaderFieldsTooLargeError(String),
+
+    #[error("Request body is larger than {0} bytes")]
+    PayloadTooLargeError(usize),
+
+    #[error("Unsupported expectation: {0}")]
+    UnsupportedExpectationError(String),
+}
+
+impl RequestError {
+    // This function maps a request parsing error to the status code of the response that is sent
+    // back to the client before the connection is closed
+    pub fn status_code(&self) -> utils::HttpStatusCode {
+        match self {
+            RequestError::UnsupportedTransferEncodingError(_) => {
+                utils::HttpStatusCode::NotImplemented
+            }
+            RequestError::RequestLineTooLongError(_) => utils::HttpStatusCode::URITooLong,
+            RequestError::HeaderFieldsTooLargeError(_) => {
+                utils::HttpStatusCode::RequestHeaderFieldsTooLarge
+            }
+            RequestError::PayloadTooLargeError(_) => utils::HttpStatusCode::PayloadTooLarge,
+            RequestError::UnsupportedExpectationError(_) => {
+                utils::HttpStatusCode::ExpectationFailed
+            }
+            _ => utils::HttpStatusCode::BadRequest,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -45,6 +94,18 @@ pub enum WebServerError {
 
     #[error("Internal server error: {0}")]
     InternalServerError(String),
+
+    #[error("TLS error: {0}")]
+    TlsError(String),
+
+    #[error("Failed to bind to {0}: {1}")]
+    BindError(String, std::io::Error),
+
+    #[error("Invalid server configuration: {0}")]
+    ConfigError(String),
+
+    #[error("PROXY protocol error: {0}")]
+    ProxyProtocolError(ProxyProtocolError),
 }
 
 impl From<ParseIntError> for WebServerError {
@@ -53,6 +114,42 @@ impl From<ParseIntError> for WebServerError {
     }
 }
 
+#[derive(Debug, Error)]
+pub enum WebSocketError {
+    #[error("I/O error: {0}")]
+    IO(#[from] std::io::Error),
+
+    #[error("WebSocket protocol error: {0}")]
+    ProtocolError(String),
+
+    #[error("WebSocket message is larger than {0} bytes")]
+    MessageTooBigError(usize),
+
+    #[error("WebSocket text message is not valid UTF-8")]
+    InvalidUtf8Error,
+
+    #[error("WebSocket connection is closed")]
+    ConnectionClosed,
+}
+
+#[derive(Debug, Error)]
+pub enum ProxyProtocolError {
+    #[error("I/O error: {0}")]
+    IO(#[from] io::Error),
+
+    #[error("Connection does not start with a PROXY protocol header")]
+    MissingHeaderError,
+
+    #[error("Invalid PROXY protocol header: {0}")]
+    InvalidHeaderError(String),
+}
+
+#[derive(Debug, Error)]
+pub enum EventStreamError {
+    #[error("The client of the event stream has disconnected")]
+    Disconnected,
+}
+
 #[derive(Debug, Error)]
 pub enum WebRouterError {
     #[error("Error while formatting a path: {0}")]
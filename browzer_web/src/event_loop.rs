@@ -0,0 +1,1247 @@
+use crate::{
+    config, connection, error, proxy_protocol, request, response, router, shutdown, stats, utils,
+    utils::thread_pool::{Job, ThreadPool},
+};
+use mio::{event::Source, net, Events, Interest, Poll, Registry, Token, Waker};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// the token an I/O thread is woken up with when there is a message for it
+const WAKER_TOKEN: Token = Token(usize::MAX);
+// how many bytes are read off a connection at once
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+// how much of a response may be waiting to be written to a connection before the handler
+// producing it is held up until the client takes some of it in
+const MAX_QUEUED_OUTPUT: usize = 256 * 1024;
+// how often an I/O thread checks the timeouts of its connections
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+// how often an I/O thread tries to send the jobs it holds back to the workers again while their
+// queue is full
+const PARKED_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+// The parts of the `WebServer` the I/O threads and the handlers they run need
+#[derive(Debug, Clone)]
+pub(crate) struct ServerContext {
+    pub(crate) router: Arc<router::WebRouter>,
+    pub(crate) config: Arc<config::ServerConfig>,
+    pub(crate) shutdown: Arc<shutdown::ShutdownState>,
+    pub(crate) counters: Arc<stats::ServerCounters>,
+    pub(crate) pool: Arc<ThreadPool>,
+}
+
+// The readiness based connection handling of the `WebServer`, see `ServerConfig::io_threads`.
+//
+// Every I/O thread runs a `mio` event loop over the connections handed to it. It reads requests
+// off them with non-blocking I/O and only sends complete `Request`s to the `ThreadPool`, the
+// responses the handlers produce are sent back to it and written out by it too, so a slow client
+// never ties up a worker thread.
+#[derive(Debug)]
+pub(crate) struct EventLoop {
+    threads: Vec<IoThread>,
+    // the I/O thread the next connection goes to, connections are dispatched round robin from
+    // the accepting thread of every listener
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct IoThread {
+    sender: mpsc::Sender<Message>,
+    waker: Arc<Waker>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl EventLoop {
+    // This function starts `threads` I/O threads
+    pub(crate) fn start(threads: usize, context: ServerContext) -> io::Result<EventLoop> {
+        let mut io_threads = Vec::with_capacity(threads);
+        for _ in 0..threads {
+            let poll = Poll::new()?;
+            let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+            let (sender, receiver) = mpsc::channel();
+            let mut io_thread = IoLoop {
+                poll,
+                sender: sender.clone(),
+                receiver,
+                waker: Arc::clone(&waker),
+                connections: HashMap::new(),
+                next_token: 0,
+                context: context.clone(),
+                parked: VecDeque::new(),
+            };
+            io_threads.push(IoThread {
+                sender,
+                waker,
+                thread: Some(thread::spawn(move || io_thread.run())),
+            });
+        }
+        return Ok(EventLoop {
+            threads: io_threads,
+            next: AtomicUsize::new(0),
+        });
+    }
+
+    // This function hands a newly accepted connection to the next I/O thread
+    pub(crate) fn dispatch(&self, stream: connection::Stream) -> io::Result<()> {
+        let io_thread =
+            &self.threads[self.next.fetch_add(1, Ordering::Relaxed) % self.threads.len()];
+        match io_thread.sender.send(Message::Connection(stream)) {
+            Ok(_) => {}
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "the I/O thread is not running",
+                ));
+            }
+        };
+        return io_thread.waker.wake();
+    }
+
+    // This function waits until the I/O threads have closed all of their connections, which they
+    // do once a shutdown is requested and the requests in flight are answered. It returns the
+    // number of I/O threads still running at `deadline`, those are detached and left running
+    pub(crate) fn join(&mut self, deadline: Instant) -> usize {
+        for io_thread in &self.threads {
+            let _ = io_thread.waker.wake();
+        }
+        while Instant::now() < deadline
+            && self
+                .threads
+                .iter()
+                .any(|io_thread| match &io_thread.thread {
+                    Some(thread) => !thread.is_finished(),
+                    None => false,
+                })
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut busy_threads = 0;
+        for io_thread in &mut self.threads {
+            if let Some(thread) = io_thread.thread.take() {
+                match thread.is_finished() {
+                    true => {
+                        let _ = thread.join();
+                    }
+                    false => busy_threads += 1,
+                }
+            }
+        }
+        return busy_threads;
+    }
+}
+
+// What an I/O thread is told through its channel
+enum Message {
+    // a new connection to serve
+    Connection(connection::Stream),
+    // a part of the response to the request being handled on a connection
+    Output(Token, Output),
+}
+
+enum Output {
+    Data(Vec<u8>),
+    // the response is complete, the connection is kept open for the next request if `true`
+    Done(bool),
+    // the response is complete and the connection is handed over to this handler
+    Upgrade(response::UpgradeHandler),
+}
+
+// A connection in non-blocking mode, registered with the event loop of an I/O thread
+enum IoStream {
+    Tcp(net::TcpStream),
+    #[cfg(unix)]
+    Unix(net::UnixStream),
+}
+
+impl IoStream {
+    // This function makes `stream` non-blocking
+    fn from_stream(stream: connection::Stream) -> io::Result<IoStream> {
+        match stream {
+            connection::Stream::Tcp(stream) => {
+                stream.set_nonblocking(true)?;
+                return Ok(IoStream::Tcp(net::TcpStream::from_std(stream)));
+            }
+            #[cfg(unix)]
+            connection::Stream::Unix(stream) => {
+                stream.set_nonblocking(true)?;
+                return Ok(IoStream::Unix(net::UnixStream::from_std(stream)));
+            }
+            // a connection is only wrapped in TLS once it is being served on a worker thread
+            #[cfg(feature = "tls")]
+            connection::Stream::Tls(_) => unreachable!(),
+        }
+    }
+
+    fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+        match self {
+            IoStream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    // This function turns the connection back into a blocking one, for a handler taking it over
+    fn into_stream(self) -> io::Result<connection::Stream> {
+        match self {
+            IoStream::Tcp(stream) => {
+                let stream = std::net::TcpStream::from(stream);
+                stream.set_nonblocking(false)?;
+                return Ok(connection::Stream::Tcp(stream));
+            }
+            #[cfg(unix)]
+            IoStream::Unix(stream) => {
+                let stream = std::os::unix::net::UnixStream::from(stream);
+                stream.set_nonblocking(false)?;
+                return Ok(connection::Stream::Unix(stream));
+            }
+        }
+    }
+}
+
+impl Read for IoStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            IoStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for IoStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            IoStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            IoStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for IoStream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            IoStream::Tcp(stream) => stream.register(registry, token, interests),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            IoStream::Tcp(stream) => stream.reregister(registry, token, interests),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            IoStream::Tcp(stream) => stream.deregister(registry),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
+
+// The part of a response that was sent to an I/O thread but not written to the connection yet,
+// shared with the handler producing the response so that it can be held up while the client is
+// slow to take it in
+#[derive(Debug, Default)]
+struct OutputQueue {
+    queued: Mutex<usize>,
+    drained: Condvar,
+    // set once the connection is closed, the rest of the response is thrown away
+    closed: AtomicBool,
+}
+
+impl OutputQueue {
+    fn written(&self, written: usize) {
+        let mut queued = match self.queued.lock() {
+            Ok(queued) => queued,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *queued = queued.saturating_sub(written);
+        self.drained.notify_all();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.drained.notify_all();
+    }
+}
+
+// The `Write` a handler's response is written to on a worker thread, every flush sends what was
+// written so far to the I/O thread of the connection
+struct ResponseWriter {
+    token: Token,
+    sender: mpsc::Sender<Message>,
+    waker: Arc<Waker>,
+    queue: Arc<OutputQueue>,
+    write_timeout: Duration,
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl ResponseWriter {
+    fn send(&self, output: Output) -> io::Result<()> {
+        match self.sender.send(Message::Output(self.token, output)) {
+            Ok(_) => {}
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "the I/O thread is not running",
+                ));
+            }
+        };
+        return self.waker.wake();
+    }
+
+    fn closed() -> io::Error {
+        return io::Error::new(io::ErrorKind::BrokenPipe, "the connection was closed");
+    }
+
+    // tells the I/O thread that the response is complete
+    fn finish(&mut self, keep_alive: bool) {
+        self.done = true;
+        let _ = self.send(Output::Done(keep_alive));
+    }
+
+    // tells the I/O thread that the response is complete and the connection is to be handed over
+    fn upgrade(&mut self, handler: response::UpgradeHandler) {
+        self.done = true;
+        let _ = self.send(Output::Upgrade(handler));
+    }
+}
+
+impl Write for ResponseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.queue.closed.load(Ordering::SeqCst) {
+            return Err(ResponseWriter::closed());
+        }
+        self.buffer.extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut queued = match self.queue.queued.lock() {
+            Ok(queued) => queued,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *queued += self.buffer.len();
+        let data = mem::take(&mut self.buffer);
+        self.send(Output::Data(data))?;
+
+        let (queued, timeout) =
+            match self
+                .queue
+                .drained
+                .wait_timeout_while(queued, self.write_timeout, |queued| {
+                    *queued > MAX_QUEUED_OUTPUT && !self.queue.closed.load(Ordering::SeqCst)
+                }) {
+                Ok(result) => result,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        if self.queue.closed.load(Ordering::SeqCst) {
+            return Err(ResponseWriter::closed());
+        }
+        if timeout.timed_out() && *queued > MAX_QUEUED_OUTPUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the client is not taking the response in",
+            ));
+        }
+        return Ok(());
+    }
+}
+
+// a handler which never finished its response (e.g. because it panicked) gets the connection
+// closed
+impl Drop for ResponseWriter {
+    fn drop(&mut self) {
+        if !self.done {
+            self.finish(false);
+        }
+    }
+}
+
+// Where a connection is in serving its current request
+enum State {
+    // waiting for the first byte of the next request on a persistent connection
+    Idle {
+        since: Instant,
+    },
+    // reading the request head, which has to be complete by `deadline`
+    Head {
+        deadline: Instant,
+    },
+    // reading the body of `request`, a chunked body is decoded as it comes in by `chunked`
+    Body {
+        request: Box<request::Request>,
+        chunked: utils::chunked::ChunkedDecoder,
+        started: Instant,
+        last_read: Instant,
+        received: u64,
+    },
+    // the request is with a handler, `done` is set once its response is complete and tells
+    // whether the connection is kept open after it
+    Handling {
+        done: Option<bool>,
+    },
+    // the response to the request is complete and the connection is handed to `handler` once
+    // it is written out
+    Upgrading {
+        handler: response::UpgradeHandler,
+    },
+    // writing out what is left of the output, then closing the connection
+    Closing,
+    // the output is written out and the sending half of the connection is shut down, what the
+    // client still sends is thrown away until `deadline` (see `connection::Stream::linger`)
+    Lingering {
+        deadline: Instant,
+        drained: usize,
+    },
+}
+
+struct Connection {
+    stream: IoStream,
+    // the addresses of the client's and the server's end of the connection, see
+    // `Request::remote_addr` and `Request::local_addr`
+    remote_addr: Option<connection::SocketAddr>,
+    local_addr: Option<connection::SocketAddr>,
+    // whether the connection has yet to send its PROXY protocol header, see
+    // `ServerConfig::proxy_protocol`
+    proxy_header_pending: bool,
+    proxy: Option<proxy_protocol::ProxyHeader>,
+    state: State,
+    // bytes read off the connection but not parsed yet
+    input: Vec<u8>,
+    // bytes waiting to be written to the connection, starting at `output_offset` of the first
+    // buffer
+    output: VecDeque<Vec<u8>>,
+    output_offset: usize,
+    queue: Arc<OutputQueue>,
+    readable: bool,
+    writable: bool,
+    last_write: Instant,
+    served_requests: usize,
+}
+
+// A job the workers' queue had no room for when an I/O thread sent it, see `IoLoop::execute`
+struct ParkedJob {
+    job: Job,
+    // the connection whose request the job handles, `None` for a connection already handed to
+    // an upgrade handler
+    token: Option<Token>,
+}
+
+// What reading the buffered input of a connection led to
+enum Progress {
+    // the buffered input does not hold a complete request yet
+    NeedMore,
+    // a complete request was handed to a handler, or the connection was answered with an error
+    Done,
+}
+
+struct IoLoop {
+    poll: Poll,
+    sender: mpsc::Sender<Message>,
+    receiver: mpsc::Receiver<Message>,
+    waker: Arc<Waker>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    context: ServerContext,
+    // the jobs waiting for a free worker, in the order they came in
+    parked: VecDeque<ParkedJob>,
+}
+
+impl IoLoop {
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(1024);
+        let mut last_sweep = Instant::now();
+        loop {
+            let timeout = match self.parked.is_empty() {
+                true => SWEEP_INTERVAL,
+                false => PARKED_RETRY_INTERVAL,
+            };
+            match self.poll.poll(&mut events, Some(timeout)) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    eprintln!("Failed to poll for connection events, Error: {}", e);
+                    break;
+                }
+            };
+
+            let mut ready = Vec::new();
+            for event in events.iter() {
+                let token = event.token();
+                if token == WAKER_TOKEN {
+                    continue;
+                }
+                if let Some(connection) = self.connections.get_mut(&token) {
+                    if event.is_readable() || event.is_read_closed() || event.is_error() {
+                        connection.readable = true;
+                    }
+                    if event.is_writable() || event.is_write_closed() || event.is_error() {
+                        connection.writable = true;
+                    }
+                    ready.push(token);
+                }
+            }
+            while let Ok(message) = self.receiver.try_recv() {
+                match message {
+                    Message::Connection(stream) => {
+                        if let Some(token) = self.register(stream) {
+                            ready.push(token);
+                        }
+                    }
+                    Message::Output(token, output) => {
+                        if let Some(connection) = self.connections.get_mut(&token) {
+                            if let State::Handling { done } = &mut connection.state {
+                                match output {
+                                    Output::Data(data) => connection.output.push_back(data),
+                                    Output::Done(keep_alive) => *done = Some(keep_alive),
+                                    Output::Upgrade(handler) => {
+                                        connection.state = State::Upgrading { handler };
+                                    }
+                                };
+                                ready.push(token);
+                            }
+                        }
+                    }
+                }
+            }
+            self.execute_parked();
+            for token in ready {
+                self.advance(token);
+            }
+
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.sweep();
+                last_sweep = Instant::now();
+            }
+            if self.context.shutdown.is_requested()
+                && self.connections.is_empty()
+                && self.parked.is_empty()
+            {
+                break;
+            }
+        }
+        for token in self.connections.keys().cloned().collect::<Vec<Token>>() {
+            self.close(token);
+        }
+    }
+
+    fn register(&mut self, stream: connection::Stream) -> Option<Token> {
+        let remote_addr = stream.peer_addr().ok();
+        let local_addr = stream.local_addr().ok();
+        let mut stream = match IoStream::from_stream(stream) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to handle incoming request, Error: {}", e);
+                self.context.counters.connection_closed();
+                return None;
+            }
+        };
+        let token = Token(self.next_token);
+        self.next_token = (self.next_token + 1) % WAKER_TOKEN.0;
+        match self.poll.registry().register(
+            &mut stream,
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Failed to handle incoming request, Error: {}", e);
+                self.context.counters.connection_closed();
+                return None;
+            }
+        };
+        let now = Instant::now();
+        self.connections.insert(
+            token,
+            Connection {
+                stream,
+                remote_addr,
+                local_addr,
+                proxy_header_pending: self.context.config.proxy_protocol,
+                proxy: None,
+                // like the blocking server, the head of the first request has to arrive within
+                // `header_read_timeout` of the connection being accepted
+                state: State::Head {
+                    deadline: now + self.context.config.header_read_timeout,
+                },
+                input: Vec::new(),
+                output: VecDeque::new(),
+                output_offset: 0,
+                queue: Arc::new(OutputQueue::default()),
+                readable: true,
+                writable: true,
+                last_write: now,
+                served_requests: 0,
+            },
+        );
+        return Some(token);
+    }
+
+    // This function moves a connection along as far as it can go without blocking: it writes out
+    // pending output, reads and parses input and hands complete requests to the handlers
+    fn advance(&mut self, token: Token) {
+        loop {
+            let connection = match self.connections.get_mut(&token) {
+                Some(connection) => connection,
+                None => return,
+            };
+            match IoLoop::write_output(connection) {
+                Ok(_) => {}
+                Err(_) => return self.close(token),
+            };
+            let output_pending = !connection.output.is_empty();
+
+            match connection.state {
+                State::Handling { done: None } => return,
+                State::Handling { done: Some(_) } | State::Upgrading { .. } | State::Closing
+                    if output_pending =>
+                {
+                    return
+                }
+                State::Handling {
+                    done: Some(keep_alive),
+                } => {
+                    self.context.counters.request_handled();
+                    if !keep_alive {
+                        return self.close(token);
+                    }
+                    connection.state = State::Idle {
+                        since: Instant::now(),
+                    };
+                }
+                State::Upgrading { .. } => {
+                    self.context.counters.request_handled();
+                    return self.upgrade(token);
+                }
+                State::Closing => {
+                    if connection
+                        .stream
+                        .shutdown(std::net::Shutdown::Write)
+                        .is_err()
+                    {
+                        return self.close(token);
+                    }
+                    connection.input.clear();
+                    connection.state = State::Lingering {
+                        deadline: Instant::now() + connection::LINGER_TIMEOUT,
+                        drained: 0,
+                    };
+                }
+                State::Lingering { .. } => match IoLoop::discard_input(connection) {
+                    Ok(false) => return,
+                    Ok(true) | Err(_) => return self.close(token),
+                },
+                State::Idle { .. } | State::Head { .. } | State::Body { .. } => {
+                    match self.parse(token) {
+                        Progress::Done => {}
+                        Progress::NeedMore => match self.read_input(token) {
+                            Ok(true) => {}
+                            // parsing may have queued output, e.g. `100 Continue`
+                            Ok(false) => match self.connections.get_mut(&token) {
+                                Some(connection) => match IoLoop::write_output(connection) {
+                                    Ok(_) => return,
+                                    Err(_) => return self.close(token),
+                                },
+                                None => return,
+                            },
+                            Err(e) => {
+                                if let Some(e) = e {
+                                    eprintln!("Failed to handle incoming request, Error: {}", e);
+                                }
+                                return self.close(token);
+                            }
+                        },
+                    };
+                }
+            };
+        }
+    }
+
+    // writes as much of the pending output as the connection takes without blocking
+    fn write_output(connection: &mut Connection) -> io::Result<()> {
+        while connection.writable {
+            let buffer = match connection.output.front() {
+                Some(buffer) => buffer,
+                None => break,
+            };
+            match connection.stream.write(&buffer[connection.output_offset..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => {
+                    connection.output_offset += n;
+                    if connection.output_offset == buffer.len() {
+                        connection.output.pop_front();
+                        connection.output_offset = 0;
+                    }
+                    connection.last_write = Instant::now();
+                    if let State::Handling { .. } = connection.state {
+                        connection.queue.written(n);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => connection.writable = false,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            };
+        }
+        return Ok(());
+    }
+
+    // This function reads and throws away what a lingering connection sends, it returns `true`
+    // once the connection can be closed
+    fn discard_input(connection: &mut Connection) -> io::Result<bool> {
+        let drained = match &mut connection.state {
+            State::Lingering { drained, .. } => drained,
+            _ => return Ok(true),
+        };
+        let mut buffer = [0; 4096];
+        while connection.readable {
+            match connection.stream.read(&mut buffer) {
+                Ok(0) => return Ok(true),
+                Ok(n) => {
+                    *drained += n;
+                    if *drained >= connection::LINGER_LIMIT {
+                        return Ok(true);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => connection.readable = false,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            };
+        }
+        return Ok(false);
+    }
+
+    // This function reads the next chunk of input off a connection. It returns `false` if there is
+    // nothing to read right now and fails at EOF or on an error, with the error to report if
+    // there is one worth reporting
+    fn read_input(&mut self, token: Token) -> Result<bool, Option<error::WebServerError>> {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return Ok(false),
+        };
+        if !connection.readable {
+            return Ok(false);
+        }
+        let start = connection.input.len();
+        connection.input.resize(start + READ_CHUNK_SIZE, 0);
+        let read = connection.stream.read(&mut connection.input[start..]);
+        connection
+            .input
+            .truncate(start + *read.as_ref().unwrap_or(&0));
+        match read {
+            Ok(0) => {
+                // a client closing the connection between requests is how persistent
+                // connections end, anywhere else the request was cut short
+                match (&connection.state, start) {
+                    (State::Idle { .. }, _) | (State::Head { .. }, 0) => return Err(None),
+                    _ => {
+                        return Err(Some(error::WebServerError::IO(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed in the middle of the request",
+                        ))));
+                    }
+                }
+            }
+            Ok(n) => {
+                let now = Instant::now();
+                match &mut connection.state {
+                    State::Idle { .. } => {
+                        connection.state = State::Head {
+                            deadline: now + self.context.config.header_read_timeout,
+                        };
+                    }
+                    State::Body {
+                        last_read,
+                        received,
+                        ..
+                    } => {
+                        *last_read = now;
+                        *received += n as u64;
+                    }
+                    _ => {}
+                };
+                return Ok(true);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                connection.readable = false;
+                return Ok(false);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(e) => return Err(Some(error::WebServerError::IO(e))),
+        }
+    }
+
+    // This function parses the buffered input of a connection as far as it goes. The parsers of
+    // `Request` are run on what was received so far, running into the end of it means the
+    // request is not complete yet
+    fn parse(&mut self, token: Token) -> Progress {
+        let limits = &self.context.config.limits;
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return Progress::NeedMore,
+        };
+
+        if let State::Idle { .. } = connection.state {
+            if connection.input.is_empty() {
+                return Progress::NeedMore;
+            }
+            connection.state = State::Head {
+                deadline: Instant::now() + self.context.config.header_read_timeout,
+            };
+        }
+
+        // a line break split between `\r` and `\n` would otherwise be taken for a complete line
+        let split_line_break = connection.input.last() == Some(&b'\r');
+
+        if let State::Head { .. } = connection.state {
+            if connection.proxy_header_pending {
+                match proxy_protocol::parse(&connection.input) {
+                    Ok(Some((header, consumed))) => {
+                        connection.input.drain(..consumed);
+                        connection.proxy = header;
+                        connection.proxy_header_pending = false;
+                    }
+                    Ok(None) => return Progress::NeedMore,
+                    Err(e) => {
+                        self.reject(token, error::WebServerError::ProxyProtocolError(e));
+                        return Progress::Done;
+                    }
+                };
+            }
+            if split_line_break {
+                return Progress::NeedMore;
+            }
+            let mut input = connection.input.as_slice();
+            match request::Request::read_head(&mut input, limits) {
+                Ok(Some(request)) => {
+                    let consumed = connection.input.len() - input.len();
+                    connection.input.drain(..consumed);
+                    match self.context.router.check_expectation(&request, limits) {
+                        Ok(router::Expectation::None) => {}
+                        Ok(router::Expectation::Continue) => {
+                            connection.output.push_back(response::CONTINUE.to_vec());
+                        }
+                        // the connection lingers once the response is written out, the client
+                        // may be sending the body already
+                        Ok(router::Expectation::Reject(mut response)) => {
+                            response.set_keep_alive(false);
+                            let mut buffer = Vec::new();
+                            let _ = response.write_to(&mut buffer, request.version != "HTTP/1.0");
+                            connection.output.push_back(buffer);
+                            connection.state = State::Closing;
+                            return Progress::Done;
+                        }
+                        Err(e) => {
+                            self.reject(token, e);
+                            return Progress::Done;
+                        }
+                    };
+                    let now = Instant::now();
+                    connection.state = State::Body {
+                        request: Box::new(request),
+                        chunked: utils::chunked::ChunkedDecoder::default(),
+                        started: now,
+                        last_read: now,
+                        received: connection.input.len() as u64,
+                    };
+                }
+                Ok(None) => return Progress::NeedMore,
+                Err(e) if is_incomplete(&e) => return Progress::NeedMore,
+                Err(e) => {
+                    self.reject(token, e);
+                    return Progress::Done;
+                }
+            };
+        }
+
+        let (request, chunked) = match &mut connection.state {
+            State::Body {
+                request, chunked, ..
+            } => (request, chunked),
+            _ => return Progress::NeedMore,
+        };
+        match request.body_framing() {
+            Ok(request::BodyFraming::Empty) => {}
+            Ok(request::BodyFraming::ContentLength(content_length)) => {
+                if content_length > limits.max_body_size {
+                    self.reject(
+                        token,
+                        error::WebServerError::RequestParseError(
+                            error::RequestError::PayloadTooLargeError(limits.max_body_size),
+                        ),
+                    );
+                    return Progress::Done;
+                }
+                if connection.input.len() < content_length {
+                    return Progress::NeedMore;
+                }
+                let mut input = &connection.input[..content_length];
+                match request.read_body(&mut input, limits) {
+                    Ok(_) => {
+                        connection.input.drain(..content_length);
+                    }
+                    Err(e) => {
+                        self.reject(token, e);
+                        return Progress::Done;
+                    }
+                };
+            }
+            // a chunked body is decoded as it arrives, every read picks up where the last one
+            // left off
+            Ok(request::BodyFraming::Chunked) => {
+                match chunked.feed(&connection.input, limits) {
+                    Ok(consumed) => {
+                        connection.input.drain(..consumed);
+                    }
+                    Err(e) => {
+                        self.reject(token, e);
+                        return Progress::Done;
+                    }
+                };
+                if !chunked.is_done() {
+                    return Progress::NeedMore;
+                }
+                let (body, trailers) = mem::take(chunked).finish();
+                if !body.is_empty() {
+                    request.body = Some(body);
+                }
+                request.trailers = trailers;
+            }
+            Err(e) => {
+                self.reject(token, error::WebServerError::RequestParseError(e));
+                return Progress::Done;
+            }
+        };
+
+        let request = match mem::replace(&mut connection.state, State::Handling { done: None }) {
+            State::Body { request, .. } => request,
+            _ => return Progress::Done,
+        };
+        self.handle(token, *request);
+        return Progress::Done;
+    }
+
+    // This function hands a complete request to the worker threads, the response is sent back
+    // through a `ResponseWriter`
+    fn handle(&mut self, token: Token, mut request: request::Request) {
+        let context = self.context.clone();
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        connection.served_requests += 1;
+        request.remote_addr = connection.remote_addr.clone();
+        request.local_addr = connection.local_addr.clone();
+        request.proxy = connection.proxy.clone();
+        request.resolve_client_ip(&context.config.trusted_proxies);
+        request.shutdown = Some(Arc::clone(&context.shutdown));
+
+        if let config::OverloadPolicy::Reject { retry_after } = &context.config.overload_policy {
+            if context.pool.is_saturated() {
+                context.counters.connection_rejected();
+                let status_code = utils::HttpStatusCode::ServiceUnavailable;
+                let mut response =
+                    response::Response::new(status_code.clone(), status_code.code().0.to_string());
+                response.headers.insert(
+                    "Retry-After".to_string(),
+                    retry_after.as_secs().max(1).to_string(),
+                );
+                response.set_keep_alive(false);
+                IoLoop::queue_response(connection, &response);
+                connection.state = State::Closing;
+                return;
+            }
+        }
+
+        let keep_alive = context.config.keep_alive
+            && request.keep_alive()
+            && (context.config.max_requests_per_connection == 0
+                || connection.served_requests < context.config.max_requests_per_connection);
+        let chunked = request.version != "HTTP/1.0";
+
+        connection.queue = Arc::new(OutputQueue::default());
+        let mut writer = ResponseWriter {
+            token,
+            sender: self.sender.clone(),
+            waker: Arc::clone(&self.waker),
+            queue: Arc::clone(&connection.queue),
+            write_timeout: context.config.write_timeout,
+            buffer: Vec::new(),
+            done: false,
+        };
+        self.execute(
+            Some(token),
+            Box::new(move || {
+                let mut response = match context.router.handle_request(request) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to handle incoming request, Error: {}",
+                            error::WebServerError::InternalServerError(e.to_string())
+                        );
+                        return writer.finish(false);
+                    }
+                };
+                let keep_alive = response.set_keep_alive(
+                    keep_alive
+                        && !context.shutdown.is_requested()
+                        && (chunked || matches!(response.body, response::Body::Full(_))),
+                );
+                match response
+                    .write_to(&mut writer, chunked)
+                    .and_then(|_| writer.flush())
+                {
+                    Ok(_) => match response.upgrade.as_ref().and_then(|upgrade| upgrade.take()) {
+                        Some(handler) => writer.upgrade(handler),
+                        None => writer.finish(keep_alive),
+                    },
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::TimedOut {
+                            context.counters.connection_timed_out();
+                        }
+                        writer.finish(false);
+                    }
+                };
+            }),
+        );
+    }
+
+    // This function sends a job to the workers. The I/O thread never waits for room in their
+    // queue, as the workers may be waiting for it to write out their responses: while the queue
+    // is full the job is held back and sent again later, after the jobs held back before it
+    fn execute(&mut self, token: Option<Token>, job: Job) {
+        if self.parked.is_empty() {
+            match self.context.pool.try_execute(job) {
+                Ok(None) => {}
+                Ok(Some(job)) => self.parked.push_back(ParkedJob { job, token }),
+                Err(e) => self.abandon(token, e),
+            };
+        } else {
+            self.parked.push_back(ParkedJob { job, token });
+        }
+    }
+
+    // This function sends the jobs held back by `execute` to the workers, for as long as there is
+    // room in their queue
+    fn execute_parked(&mut self) {
+        while let Some(parked) = self.parked.pop_front() {
+            match self.context.pool.try_execute(parked.job) {
+                Ok(None) => {}
+                Ok(Some(job)) => {
+                    self.parked.push_front(ParkedJob {
+                        job,
+                        token: parked.token,
+                    });
+                    return;
+                }
+                Err(e) => self.abandon(parked.token, e),
+            };
+        }
+    }
+
+    // This function closes the connection of a job which could not be sent to the workers
+    fn abandon(&mut self, token: Option<Token>, e: error::ThreadPoolError) {
+        eprintln!(
+            "Failed to assign Worker thread to incoming request, Error: {}",
+            e
+        );
+        match token {
+            Some(token) => {
+                if let Some(connection) = self.connections.get_mut(&token) {
+                    connection.state = State::Closing;
+                }
+            }
+            None => self.context.counters.connection_closed(),
+        };
+    }
+
+    // This function answers a request which could not be read with an error response, if there is
+    // one that fits the error, and closes the connection. See `WebServer::reject_request`
+    fn reject(&mut self, token: Token, e: error::WebServerError) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        eprintln!("Failed to handle incoming request, Error: {}", e);
+        connection.state = State::Closing;
+        let status_code = match &e {
+            error::WebServerError::RequestParseError(parse_error) => parse_error.status_code(),
+            _ if utils::is_timeout(&e) => {
+                self.context.counters.connection_timed_out();
+                utils::HttpStatusCode::RequestTimeout
+            }
+            _ => return,
+        };
+        let mut response =
+            response::Response::new(status_code.clone(), status_code.code().0.to_string());
+        response.set_keep_alive(false);
+        IoLoop::queue_response(connection, &response);
+    }
+
+    fn queue_response(connection: &mut Connection, response: &response::Response) {
+        let mut buffer = Vec::new();
+        let _ = response.write_to(&mut buffer, false);
+        connection.output.push_back(buffer);
+    }
+
+    // This function enforces the timeouts of the `ServerConfig` on every connection, and closes
+    // the idle ones once a shutdown is requested
+    fn sweep(&mut self) {
+        let config = Arc::clone(&self.context.config);
+        let shutdown = self.context.shutdown.is_requested();
+        let now = Instant::now();
+        let mut timed_out = Vec::new();
+        let mut idle = Vec::new();
+        for (token, connection) in &self.connections {
+            match &connection.state {
+                State::Idle { since } => {
+                    if shutdown || now.duration_since(*since) >= config.keep_alive_timeout {
+                        idle.push(*token);
+                    }
+                }
+                State::Head { deadline } => {
+                    if now >= *deadline {
+                        timed_out.push((*token, "request head"));
+                    }
+                }
+                State::Body {
+                    started,
+                    last_read,
+                    received,
+                    ..
+                } => {
+                    let too_slow = match config.min_transfer_rate {
+                        Some(min_transfer_rate) => connection::below_min_transfer_rate(
+                            min_transfer_rate,
+                            *received,
+                            now.duration_since(*started),
+                        ),
+                        None => false,
+                    };
+                    if too_slow || now.duration_since(*last_read) >= config.body_read_timeout {
+                        timed_out.push((*token, "request body"));
+                    }
+                }
+                State::Lingering { deadline, .. } => {
+                    if now >= *deadline {
+                        idle.push(*token);
+                    }
+                }
+                State::Handling { .. } | State::Upgrading { .. } | State::Closing => {
+                    if !connection.output.is_empty()
+                        && now.duration_since(connection.last_write) >= config.write_timeout
+                    {
+                        self.context.counters.connection_timed_out();
+                        idle.push(*token);
+                    }
+                }
+            };
+        }
+
+        for token in idle {
+            self.close(token);
+        }
+        for (token, phase) in timed_out {
+            self.reject(
+                token,
+                error::WebServerError::IO(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("reading the {} timed out", phase),
+                )),
+            );
+            self.advance(token);
+        }
+    }
+
+    // This function hands a connection over to the handler taking it, see `response::Upgrade`.
+    // It is served on a worker thread from then on, with what the client already sent past the
+    // request
+    fn upgrade(&mut self, token: Token) {
+        let mut connection = match self.connections.remove(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let _ = self.poll.registry().deregister(&mut connection.stream);
+        let handler = match connection.state {
+            State::Upgrading { handler } => handler,
+            _ => return self.context.counters.connection_closed(),
+        };
+        let stream = match connection.stream.into_stream().and_then(|stream| {
+            stream.set_write_timeout(Some(self.context.config.write_timeout))?;
+            return Ok(stream);
+        }) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to hand over the connection, Error: {}", e);
+                return self.context.counters.connection_closed();
+            }
+        };
+        let upgraded = connection::Upgraded::new(
+            connection::TimedStream::new(stream),
+            connection.input,
+            self.context.config.limits.max_body_size,
+            Arc::clone(&self.context.shutdown),
+        );
+        let counters = Arc::clone(&self.context.counters);
+        self.execute(
+            None,
+            Box::new(move || {
+                (handler)(upgraded);
+                counters.connection_closed();
+            }),
+        );
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+            connection.queue.close();
+            self.context.counters.connection_closed();
+        }
+    }
+}
+
+// whether parsing failed only because the input ended before the request did
+fn is_incomplete(e: &error::WebServerError) -> bool {
+    match e {
+        error::WebServerError::IO(e) => return e.kind() == io::ErrorKind::UnexpectedEof,
+        _ => return false,
+    }
+}
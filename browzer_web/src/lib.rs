@@ -1,50 +1,146 @@
 use std::{
     fs,
-    io::{BufRead, BufReader, Read, Write},
-    net::{TcpListener, TcpStream},
+    io::{self, BufRead, BufReader, Write},
+    net,
     path::Path,
     sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
+#[cfg(feature = "tokio")]
+pub mod async_server;
+pub mod builder;
+pub mod config;
+pub mod connection;
 pub mod context;
 pub mod error;
+mod event_loop;
+pub mod proxy_protocol;
 pub mod request;
 pub mod response;
 pub mod router;
+pub mod shutdown;
+pub mod sse;
+pub mod stats;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod utils;
+pub mod websocket;
 
+// how long the accepting thread spends on writing a `503 Service Unavailable` to a connection it
+// turns away, and on reading what the client already sent on it
+const OVERLOAD_RESPONSE_TIMEOUT: Duration = Duration::from_millis(50);
 
 #[derive(Debug)]
 pub struct WebServer {
-    pub listener: TcpListener,
-    request_pool: utils::thread_pool::ThreadPool,
+    // the sockets the server accepts connections on, see `WebServerBuilder::bind`
+    pub listeners: Vec<connection::Listener>,
+    request_pool: Arc<utils::thread_pool::ThreadPool>,
     pub hide_banner: bool,
-    pub address: String,
+    pub addresses: Vec<String>,
+    // the Unix domain sockets created by the builder, they are removed when `listen` returns
+    #[cfg(unix)]
+    unix_socket_paths: Vec<std::path::PathBuf>,
+    pub config: config::ServerConfig,
     router: Arc<router::WebRouter>,
+    shutdown: Arc<shutdown::ShutdownState>,
+    counters: Arc<stats::ServerCounters>,
 }
 
 impl WebServer {
+    // This function creates a server listening on `address` with `workers` worker threads, it
+    // panics if the server can not be created, use `WebServer::builder` to handle that instead
     pub fn new(address: String, workers: usize) -> WebServer {
-        let listener = match TcpListener::bind(&address) {
-            Ok(listener) => listener,
+        match WebServer::builder().bind(&address).workers(workers).build() {
+            Ok(server) => server,
             Err(listener_create_err) => {
                 panic!(
                     "Failed to create listener for the WebServer, Error: {}",
                     listener_create_err.to_string()
                 );
             }
-        };
+        }
+    }
 
-        let request_pool = utils::thread_pool::ThreadPool::new(workers);
+    // This function creates a server accepting connections on a socket which is already
+    // listening (e.g. one inherited from a parent process) with `workers` worker threads, it
+    // panics if the server can not be created, see `WebServerBuilder::listener`
+    pub fn from_listener(listener: net::TcpListener, workers: usize) -> WebServer {
+        match WebServer::builder()
+            .listener(listener)
+            .workers(workers)
+            .build()
+        {
+            Ok(server) => server,
+            Err(listener_create_err) => {
+                panic!(
+                    "Failed to create the WebServer from the listener, Error: {}",
+                    listener_create_err
+                );
+            }
+        }
+    }
 
-        // return the WebServer struct
-        return WebServer {
-            listener,
-            request_pool,
-            hide_banner: false,
-            address,
-            router: Arc::new(router::WebRouter::new()),
-        };
+    // This function creates a server accepting connections on the socket passed by systemd socket
+    // activation with `workers` worker threads, it panics if no socket was passed, see
+    // `WebServerBuilder::systemd_socket` to handle that instead
+    #[cfg(unix)]
+    pub fn from_systemd(workers: usize) -> WebServer {
+        match WebServer::builder()
+            .systemd_socket()
+            .workers(workers)
+            .build()
+        {
+            Ok(server) => server,
+            Err(listener_create_err) => {
+                panic!(
+                    "Failed to create the WebServer from the systemd socket, Error: {}",
+                    listener_create_err
+                );
+            }
+        }
+    }
+
+    // This function returns a builder to configure the server with, unlike `new` it returns an
+    // error from `build` when the server can not be created
+    pub fn builder() -> builder::WebServerBuilder {
+        return builder::WebServerBuilder::new();
+    }
+
+    // This method turns on TLS for the server, every accepted connection goes through a TLS
+    // handshake using the PEM encoded certificate chain and private key before any request is
+    // read from it. It fails if the server serves its connections from I/O threads, which can
+    // not do TLS
+    #[cfg(feature = "tls")]
+    pub fn enable_tls<P: AsRef<Path>>(
+        &mut self,
+        cert_chain_path: P,
+        key_path: P,
+    ) -> Result<(), error::WebServerError> {
+        let acceptor = tls::TlsAcceptor::from_pem_files(cert_chain_path, key_path)?;
+        self.config.tls = Some(acceptor);
+        match self.config.validate() {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.config.tls = None;
+                Err(e)
+            }
+        }
+    }
+
+    // This method turns the server into an `AsyncWebServer`, which serves the routes and
+    // middlewares registered so far from a tokio runtime instead of the worker threads. It fails
+    // if TLS is enabled, which the async server does not support
+    #[cfg(feature = "tokio")]
+    pub fn into_async(self) -> Result<async_server::AsyncWebServer, error::WebServerError> {
+        return async_server::AsyncWebServer::from_server(self);
+    }
+
+    // This method returns a handle which can be used to shut the server down gracefully from
+    // another thread once it is listening, see `ServerHandle::shutdown`
+    pub fn handle(&self) -> shutdown::ServerHandle {
+        return shutdown::ServerHandle::new(Arc::clone(&self.shutdown), Arc::clone(&self.counters));
     }
 
     // This method allows you to register a new middleware function in the ruoter's middleware
@@ -65,6 +161,25 @@ impl WebServer {
         };
     }
 
+    // This method registers a function deciding whether a request sending `Expect: 100-continue`
+    // gets to send its body. It is called with the request before its body is read, returning a
+    // response (e.g. `ExpectationFailed`, `PayloadTooLarge` or `Unauthorized`) turns the request
+    // down with it, returning `None` lets the client go on with `100 Continue`
+    pub fn expect_continue<F>(&mut self, handler: F)
+    where
+        F: Fn(&request::Request) -> Option<response::Response> + 'static + Send + Sync,
+    {
+        match Arc::get_mut(&mut self.router) {
+            Some(router) => router.set_expectation_handler(handler),
+            None => eprintln!(
+                "{}",
+                error::WebServerError::InternalServerError(
+                    "WebRouter is not innitialized".to_string()
+                )
+            ),
+        };
+    }
+
     pub fn get<F>(&mut self, path: &str, handler: F)
     where
         F: Fn(context::Context) -> response::Response + 'static + Send + Sync,
@@ -157,12 +272,68 @@ impl WebServer {
             ),
         };
     }
+    pub fn put<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(context::Context) -> response::Response + 'static + Send + Sync,
+    {
+        self.method(utils::HttpMethod::PUT, path, handler);
+    }
+    // registering a HEAD route is only needed when it should not be answered by the GET route
+    pub fn head<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(context::Context) -> response::Response + 'static + Send + Sync,
+    {
+        self.method(utils::HttpMethod::HEAD, path, handler);
+    }
+    // registering an OPTIONS route replaces the automatic answer listing the route's methods
+    pub fn options<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(context::Context) -> response::Response + 'static + Send + Sync,
+    {
+        self.method(utils::HttpMethod::OPTIONS, path, handler);
+    }
+
+    // This method registers a WebSocket endpoint. A `GET` request to `path` asking for a WebSocket
+    // is answered with `101 Switching Protocols`, then `handler` gets the context of the request and
+    // the connection as a `websocket::WebSocket`, other requests to `path` get `426 Upgrade
+    // Required`. The handler keeps a worker thread busy for as long as it runs
+    pub fn websocket<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(context::Context, websocket::WebSocket) + 'static + Send + Sync,
+    {
+        let handler = Arc::new(handler);
+        self.get(path, move |context| {
+            websocket::accept(context, Arc::clone(&handler))
+        });
+    }
+
+    // This method registers a route for any method, including `TRACE`, `CONNECT` and extension
+    // methods such as `HttpMethod::Extension("PURGE".to_string())`
+    pub fn method<F>(&mut self, method: utils::HttpMethod, path: &str, handler: F)
+    where
+        F: Fn(context::Context) -> response::Response + 'static + Send + Sync,
+    {
+        match Arc::get_mut(&mut self.router) {
+            Some(router) => match router.add(path.to_string(), method, Box::new(handler)) {
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("{}", e);
+                }
+            },
+            None => eprintln!(
+                "{}",
+                error::WebServerError::InternalServerError(
+                    "WebRouter is not innitialized".to_string()
+                )
+            ),
+        };
+    }
 
     // This method does it's function by registering a dynamic GET method route to the
     // `route_path`, that route's handler function gets the filename of the file that is requested
     // from the dynamic route params and then check if a file with that name exists under the
-    // `dir_path`, if it does then the handler will return a `String` response with that file's
-    // content as body, it not then it returns a `NotFound`
+    // `dir_path`, if it does then the handler will return a response with that file's raw bytes as
+    // body, it not then it returns a `NotFound`
     pub fn serve_static(&mut self, dir_path: &str, route_path: &str) {
         let dir_path = Arc::new(dir_path.to_string());
         let dir_path_clone = Arc::clone(&dir_path);
@@ -182,12 +353,12 @@ impl WebServer {
             let path = Path::new(&*dir_path_clone).join(filename); // NOTE: I have NO idea what is happening here
             match path.exists() {
                 true => {
-                    return c.send_string(
+                    return c.send_bytes(
                         utils::HttpStatusCode::OK,
-                        &match fs::read_to_string(path) {
+                        &match fs::read(path) {
                             Ok(res) => res,
                             Err(_) => {
-                                // Couldn't prase the path to string
+                                // Couldn't read the file
                                 return c.send_string(
                                     utils::HttpStatusCode::InternalServerError,
                                     utils::HttpStatusCode::InternalServerError.code().0,
@@ -210,21 +381,171 @@ impl WebServer {
     // This method starts the web server, accepting incoming connections and distributing
     // them to worker threads for handling. It uses the `request_pool` to manage a pool of
     // worker threads and assigns incoming requests to these workers. The function will
-    // continue to listen for connections indefinitely.
-    pub fn listen(&self) {
+    // continue to listen for connections until a shutdown is requested through a
+    // `ServerHandle`, then it waits for the requests in flight to finish (for at most
+    // `shutdown_timeout`), shuts the worker threads down and returns.
+    pub fn listen(&mut self) {
+        // `config` may have been changed since the server was built
+        if let Err(e) = self.config.validate() {
+            eprintln!("Failed to start the WebServer, Error: {}", e);
+            return;
+        }
+
         // print the server banner( a simple log message ) accoding to the `address` field boolean variable
         if !self.hide_banner {
-            println!("-----> HTTP server running on {}", self.address);
+            #[cfg(feature = "tls")]
+            let scheme = match self.config.tls {
+                Some(_) => "HTTPS",
+                None => "HTTP",
+            };
+            #[cfg(not(feature = "tls"))]
+            let scheme = "HTTP";
+            println!(
+                "-----> {} server running on {}",
+                scheme,
+                self.addresses.join(", ")
+            );
+        }
+
+        let config = Arc::new(self.config.clone());
+
+        for listener in &self.listeners {
+            match listener.local_addr() {
+                Ok(address) => self.shutdown.register(address),
+                Err(e) => eprintln!(
+                    "Failed to get the local address of the listener, Error: {}",
+                    e
+                ),
+            };
+        }
+
+        // with I/O threads the connections are handed to their event loops, which send only the
+        // complete requests to the `request_pool`
+        let event_loop = match config.io_threads {
+            0 => None,
+            io_threads => match event_loop::EventLoop::start(
+                io_threads,
+                event_loop::ServerContext {
+                    router: Arc::clone(&self.router),
+                    config: Arc::clone(&config),
+                    shutdown: Arc::clone(&self.shutdown),
+                    counters: Arc::clone(&self.counters),
+                    pool: Arc::clone(&self.request_pool),
+                },
+            ) {
+                Ok(event_loop) => Some(event_loop),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to start the I/O threads, serving connections from the worker threads instead, Error: {}",
+                        e
+                    );
+                    None
+                }
+            },
+        };
+
+        // every listener gets an accepting thread of its own, all of them hand their connections
+        // to the same `request_pool` (or I/O threads)
+        let server = &*self;
+        thread::scope(|scope| {
+            for listener in server.listeners.iter().skip(1) {
+                let config = &config;
+                let event_loop = event_loop.as_ref();
+                match thread::Builder::new().spawn_scoped(scope, move || {
+                    server.accept_connections(listener, config, event_loop)
+                }) {
+                    Ok(_) => {}
+                    Err(e) => eprintln!(
+                        "Failed to start accepting connections on a listener, Error: {}",
+                        e
+                    ),
+                };
+            }
+            if let Some(listener) = server.listeners.first() {
+                server.accept_connections(listener, &config, event_loop.as_ref());
+            }
+        });
+
+        // graceful shutdown, let the I/O threads and the worker threads finish the requests in
+        // flight
+        let deadline = Instant::now() + self.config.shutdown_timeout;
+        if let Some(mut event_loop) = event_loop {
+            let busy_io_threads = event_loop.join(deadline);
+            if busy_io_threads > 0 {
+                eprintln!(
+                    "Shutdown timed out with {} I/O threads still serving connections",
+                    busy_io_threads
+                );
+            }
+        }
+        let busy_workers = self
+            .request_pool
+            .shutdown(deadline.saturating_duration_since(Instant::now()));
+        if busy_workers > 0 {
+            eprintln!(
+                "Shutdown timed out with {} requests still in flight",
+                busy_workers
+            );
         }
+        #[cfg(unix)]
+        connection::remove_unix_sockets(&self.unix_socket_paths);
+    }
 
+    // This function accepts the connections arriving on `listener` until a shutdown is requested
+    // and hands them to the worker threads, or to the I/O threads if there are any
+    fn accept_connections(
+        &self,
+        listener: &connection::Listener,
+        config: &Arc<config::ServerConfig>,
+        event_loop: Option<&event_loop::EventLoop>,
+    ) {
         // loop over incoming requests and send those request as jobs to the `request_pool` in
         // order to be distributed to the worker threads
-        for stream in self.listener.incoming() {
+        loop {
+            // the address of the listener is registered to be woken up on shutdown by now, a
+            // shutdown requested before that would not wake up the `accept` below
+            if self.shutdown.is_requested() {
+                break;
+            }
+            let stream = listener.accept();
+            if self.shutdown.is_requested() {
+                break;
+            }
             let router = Arc::clone(&self.router);
+            let config = Arc::clone(config);
+            let shutdown = Arc::clone(&self.shutdown);
+            let counters = Arc::clone(&self.counters);
             match stream {
                 Ok(stream) => {
-                    match self.request_pool.execute(|| {
-                        match Self::handle_request(router, stream) {
+                    if let Some(event_loop) = event_loop {
+                        self.counters.connection_opened();
+                        match event_loop.dispatch(stream) {
+                            Ok(_) => {}
+                            Err(e) => {
+                                self.counters.connection_closed();
+                                eprintln!(
+                                    "Failed to assign I/O thread to incoming connection, Error: {}",
+                                    e
+                                );
+                            }
+                        };
+                        continue;
+                    }
+                    if let config::OverloadPolicy::Reject { retry_after } =
+                        &self.config.overload_policy
+                    {
+                        if self.request_pool.is_saturated() {
+                            self.counters.connection_rejected();
+                            Self::reject_overloaded(stream, &config, *retry_after);
+                            continue;
+                        }
+                    }
+                    // the connection stops being counted as active when the job is dropped,
+                    // whether it ran to the end, panicked or could not be queued at all
+                    let connection = stats::OpenConnection::new(Arc::clone(&self.counters));
+                    match self.request_pool.execute(move || {
+                        let _connection = connection;
+                        match Self::handle_request(router, config, shutdown, &counters, stream) {
                             Ok(_) => {}
                             Err(e) => {
                                 eprintln!("Failed to handle incoming request, Error: {}", e);
@@ -232,10 +553,12 @@ impl WebServer {
                         };
                     }) {
                         Ok(_) => {}
-                        Err(e) => eprintln!(
-                            "Failed to assign Worker thread to incoming request, Error: {}",
-                            e.to_string()
-                        ),
+                        Err(e) => {
+                            eprintln!(
+                                "Failed to assign Worker thread to incoming request, Error: {}",
+                                e.to_string()
+                            )
+                        }
                     };
                 }
                 Err(e) => {
@@ -245,84 +568,298 @@ impl WebServer {
         }
     }
 
+    // This function serves a single client connection. It keeps reading requests off the same
+    // stream and writing their responses back for as long as both the client and the server
+    // agree to keep the connection alive (see `Request::keep_alive`), the connection sits idle
+    // for less than `keep_alive_timeout`, `max_requests_per_connection` is not reached and the
+    // server is not shutting down
     fn handle_request(
         router: Arc<router::WebRouter>,
-        mut stream: TcpStream,
+        config: Arc<config::ServerConfig>,
+        shutdown: Arc<shutdown::ShutdownState>,
+        counters: &stats::ServerCounters,
+        stream: connection::Stream,
     ) -> Result<(), error::WebServerError> {
-        let mut buf_reader = BufReader::new(&mut stream);
-
-        // parse the request string into a `Request` struct by first parsing the string to a string
-        // vector containling the lines of requests as elements by following cases:-
-        //
-        // - if the headers contain the `Content-Length` header and it's value is more than 0, then
-        //   we properly parse the body too
-        // - if the headers do not contain the `Content-Length` then we stop after parsing
-        //
-        // and then passing that vector onto the `new` function of the `Request` string as input
-        let request = match request::Request::new(&{
-            let mut request_vector = Vec::new();
-            let mut content_length = 0;
-
-            for line in buf_reader.by_ref().lines() {
-                let line = match line {
-                    Ok(ln) => ln,
-                    Err(e) => return Err(error::WebServerError::IO(e)),
-                };
-                match line.strip_prefix("Content-Length: ") {
-                    Some(c_l) => {
-                        content_length = match c_l.trim().parse() {
-                            Ok(safe_c_l) => safe_c_l,
-                            Err(e) => return Err(error::WebServerError::from(e)),
+        stream.set_write_timeout(Some(config.write_timeout))?;
+        let remote_addr = stream.peer_addr().ok();
+        let local_addr = stream.local_addr().ok();
+        // the addresses a load balancer relays with the PROXY protocol, they are sent ahead of
+        // the first request (and of the TLS handshake)
+        let mut proxy = None;
+
+        // wrap the accepted connection in TLS first if it is enabled, the handshake (along with
+        // the PROXY protocol header) has to be over within `header_read_timeout` too
+        #[cfg(feature = "tls")]
+        let stream = match (&config.tls, stream) {
+            (Some(acceptor), connection::Stream::Tcp(stream)) => {
+                let deadline = Instant::now() + config.header_read_timeout;
+                let mut received = Vec::new();
+                if config.proxy_protocol {
+                    let mut reader = BufReader::new(tls::DeadlineSocket::new(&stream, deadline));
+                    match proxy_protocol::read_header(&mut reader) {
+                        Ok(header) => proxy = header,
+                        Err(e) => return Err(error::WebServerError::ProxyProtocolError(e)),
+                    };
+                    received = reader.buffer().to_vec();
+                }
+                match acceptor.accept(stream, &received, deadline) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        if utils::is_timeout(&e) {
+                            counters.connection_timed_out();
                         }
+                        return Err(e);
                     }
-                    None => {}
-                }
-                if line.is_empty() {
-                    request_vector.push(line);
-                    break;
                 }
-                request_vector.push(line);
             }
-            let mut body = Vec::new();
-            if content_length > 0 {
-                body.resize(content_length, 0);
-                match buf_reader.take(content_length as u64).read_exact(&mut body) {
-                    Ok(_) => {}
-                    Err(e) => return Err(error::WebServerError::IO(e)),
-                }
-                request_vector.push(String::from_utf8_lossy(&body).to_string());
-            }
-            request_vector // return the request_vector to Request::new() function
-        }) {
-            Ok(safe) => safe,
-            Err(e) => {
-                return Err(error::WebServerError::RequestParseError(e));
+            (Some(_), _) => {
+                return Err(error::WebServerError::TlsError(
+                    "TLS is only supported over TCP connections".to_string(),
+                ));
             }
+            (None, stream) => stream,
         };
+        let tls_info = stream.tls_info();
+
+        let mut buf_reader = BufReader::new(connection::TimedStream::new(stream));
+        let mut served_requests = 0;
+        if config.proxy_protocol && !config.tls_enabled() {
+            buf_reader.get_mut().set_limits(
+                Some(Instant::now() + config.header_read_timeout),
+                None,
+                None,
+            );
+            match proxy_protocol::read_header(&mut buf_reader) {
+                Ok(header) => proxy = header,
+                Err(e) => return Err(error::WebServerError::ProxyProtocolError(e)),
+            };
+        }
+
+        loop {
+            // wait for the next request on a persistent connection, the connection is closed
+            // quietly if the client closes it, stays idle for longer than `keep_alive_timeout` or
+            // the server starts shutting down in the meantime
+            if served_requests > 0 {
+                match Self::wait_for_request(&mut buf_reader, &config, &shutdown) {
+                    Ok(true) => {}
+                    Ok(false) => return Ok(()),
+                    Err(e) => return Err(error::WebServerError::IO(e)),
+                };
+            }
 
-        // utilize user registered routes from `routes` hashmap in the `WebRouter` to handle
-        // requests, generate responses and then send those responses to the request agent throught
-        // the TCP connection stream
-        match stream.write_all(
-            match router.handle_request(request) {
-                Ok(res) => res.to_string(),
+            // parse the next request off the stream into a `Request` struct, a malformed or too
+            // slow request is answered with an error response before the connection is closed as
+            // there is no telling where the next request would start.
+            //
+            // the head has to arrive within `header_read_timeout` while the body only has to keep
+            // coming in, at `min_transfer_rate` or faster
+            buf_reader.get_mut().set_limits(
+                Some(Instant::now() + config.header_read_timeout),
+                None,
+                None,
+            );
+            let mut request = match request::Request::read_head(&mut buf_reader, &config.limits) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(e) => return Self::reject_request(&mut buf_reader, counters, e),
+            };
+            // a client sending `Expect: 100-continue` waits for the go-ahead before it sends the
+            // body, or gets turned down without having to send it
+            match router.check_expectation(&request, &config.limits) {
+                Ok(router::Expectation::None) => {}
+                Ok(router::Expectation::Continue) => {
+                    let stream = buf_reader.get_mut();
+                    match stream
+                        .write_all(response::CONTINUE)
+                        .and_then(|_| stream.flush())
+                    {
+                        Ok(_) => {}
+                        Err(e) => return Err(error::WebServerError::IO(e)),
+                    };
+                }
+                // the client may not wait for `100 Continue` any longer and be sending the body
+                // already, see `Stream::linger`
+                Ok(router::Expectation::Reject(mut response)) => {
+                    response.set_keep_alive(false);
+                    let stream = buf_reader.get_mut();
+                    match response.write_to(stream, request.version != "HTTP/1.0") {
+                        Ok(_) => {
+                            stream.linger();
+                            return Ok(());
+                        }
+                        Err(e) => return Err(error::WebServerError::IO(e)),
+                    };
+                }
+                Err(e) => return Self::reject_request(&mut buf_reader, counters, e),
+            };
+
+            buf_reader.get_mut().set_limits(
+                None,
+                Some(config.body_read_timeout),
+                config.min_transfer_rate,
+            );
+            match request.read_body(&mut buf_reader, &config.limits) {
+                Ok(_) => {}
+                Err(e) => return Self::reject_request(&mut buf_reader, counters, e),
+            };
+            buf_reader.get_mut().set_limits(None, None, None);
+            served_requests += 1;
+            request.tls = tls_info.clone();
+            request.remote_addr = remote_addr.clone();
+            request.local_addr = local_addr.clone();
+            request.proxy = proxy.clone();
+            request.resolve_client_ip(&config.trusted_proxies);
+            request.shutdown = Some(Arc::clone(&shutdown));
+
+            let keep_alive = config.keep_alive
+                && request.keep_alive()
+                && (config.max_requests_per_connection == 0
+                    || served_requests < config.max_requests_per_connection);
+            // clients older than HTTP/1.1 do not understand chunked encoding, a streaming body is
+            // sent to them as is and ended by closing the connection
+            let chunked = request.version != "HTTP/1.0";
+
+            // utilize user registered routes from `routes` hashmap in the `WebRouter` to handle
+            // requests, generate responses and then send those responses to the request agent
+            // throught the TCP connection stream
+            let mut response = match router.handle_request(request) {
+                Ok(res) => res,
                 Err(e) => {
                     return Err(error::WebServerError::InternalServerError(e.to_string()));
                 }
+            };
+            let keep_alive = response.set_keep_alive(
+                keep_alive
+                    && !shutdown.is_requested()
+                    && (chunked || matches!(response.body, response::Body::Full(_))),
+            );
+
+            // pipelined requests are answered in the order they came in, while the next one is
+            // already buffered the response is held back to go out together with the next ones
+            // (see `TimedStream`)
+            let stream = buf_reader.get_mut();
+            match response.write_to(stream, chunked) {
+                Ok(_) => {}
+                Err(e) => {
+                    let e = error::WebServerError::IO(e);
+                    if utils::is_timeout(&e) {
+                        counters.connection_timed_out();
+                    }
+                    return Err(e);
+                }
+            };
+            if !keep_alive || buf_reader.buffer().is_empty() {
+                match buf_reader.get_mut().flush() {
+                    Ok(_) => {}
+                    Err(e) => {
+                        return Err(error::WebServerError::StreamFlushError(e.to_string()));
+                    }
+                };
             }
-            .as_bytes(),
-        ) {
-            Ok(_) => {}
-            Err(e) => {
-                return Err(error::WebServerError::IO(e));
+            counters.request_handled();
+
+            // a handler taking the connection over gets it along with what the client already sent
+            // past the request
+            if let Some(upgrade) = response.upgrade.as_ref().and_then(|upgrade| upgrade.take()) {
+                let buffered = buf_reader.buffer().to_vec();
+                (upgrade)(connection::Upgraded::new(
+                    buf_reader.into_inner(),
+                    buffered,
+                    config.limits.max_body_size,
+                    Arc::clone(&shutdown),
+                ));
+                return Ok(());
             }
-        };
 
-        match stream.flush() {
-            Ok(_) => Ok({}),
-            Err(e) => {
-                return Err(error::WebServerError::StreamFlushError(e.to_string()));
+            if !keep_alive {
+                return Ok(());
             }
         }
     }
+
+    // This function waits for the first bytes of the next request on an idle persistent
+    // connection. It returns `false` if the connection should be closed instead, because the
+    // client closed it, it stayed idle for longer than `keep_alive_timeout` or the server is
+    // shutting down. The wait is split into short slices so that a shutdown is noticed quickly.
+    fn wait_for_request(
+        buf_reader: &mut BufReader<connection::TimedStream>,
+        config: &config::ServerConfig,
+        shutdown: &shutdown::ShutdownState,
+    ) -> io::Result<bool> {
+        let deadline = Instant::now() + config.keep_alive_timeout;
+        loop {
+            if shutdown.is_requested() {
+                return Ok(false);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            buf_reader.get_mut().set_limits(
+                Some(deadline.min(now + Duration::from_millis(100))),
+                None,
+                None,
+            );
+            match buf_reader.fill_buf() {
+                Ok(buf) => return Ok(!buf.is_empty()),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    // This function turns a connection away with `503 Service Unavailable` without handing it to
+    // the worker threads. It runs on the accepting thread, so writing the response is bounded by a
+    // short timeout, and a TLS connection is just closed since answering it would first need a
+    // handshake
+    fn reject_overloaded(
+        mut stream: connection::Stream,
+        config: &config::ServerConfig,
+        retry_after: Duration,
+    ) {
+        if config.tls_enabled() {
+            return;
+        }
+
+        let status_code = utils::HttpStatusCode::ServiceUnavailable;
+        let mut response =
+            response::Response::new(status_code.clone(), status_code.code().0.to_string());
+        response.headers.insert(
+            "Retry-After".to_string(),
+            retry_after.as_secs().max(1).to_string(),
+        );
+        response.set_keep_alive(false);
+        let _ = stream.set_write_timeout(Some(OVERLOAD_RESPONSE_TIMEOUT));
+        let _ = response.write_to(&mut stream, false);
+        let _ = stream.flush();
+
+        // a client trickling in the rest of its request would hold up the accepting thread, so
+        // lingering on the connection is cut a lot shorter than usual
+        stream.linger(64 * 1024, OVERLOAD_RESPONSE_TIMEOUT);
+    }
+
+    // This function answers a request which could not be read with an error response, if there is
+    // one that fits the error, and hands the error back so that the connection gets closed. The
+    // client may still be sending the rest of the request, see `Stream::linger`
+    fn reject_request(
+        buf_reader: &mut BufReader<connection::TimedStream>,
+        counters: &stats::ServerCounters,
+        e: error::WebServerError,
+    ) -> Result<(), error::WebServerError> {
+        let status_code = match &e {
+            error::WebServerError::RequestParseError(parse_error) => parse_error.status_code(),
+            _ if utils::is_timeout(&e) => {
+                counters.connection_timed_out();
+                utils::HttpStatusCode::RequestTimeout
+            }
+            _ => return Err(e),
+        };
+        let mut response =
+            response::Response::new(status_code.clone(), status_code.code().0.to_string());
+        response.set_keep_alive(false);
+        let stream = buf_reader.get_mut();
+        let _ = response.write_to(stream, false);
+        stream.linger();
+        return Err(e);
+    }
 }
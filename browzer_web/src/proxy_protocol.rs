@@ -0,0 +1,431 @@
+use crate::error;
+use std::{
+    io::BufRead,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+// the start of a version 1 (text) header
+const V1_PREFIX: &[u8] = b"PROXY ";
+// the longest a version 1 header can be, line break included
+const V1_MAX_LENGTH: usize = 107;
+// the start of a version 2 (binary) header
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// the signature, the version and command, the address family and the length of the addresses
+const V2_HEADER_LENGTH: usize = 16;
+
+// The addresses of the connection a proxy (e.g. HAProxy) accepted from the client, relayed to the
+// server with the PROXY protocol, see `ServerConfig::proxy_protocol`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyHeader {
+    // the address of the client the proxy accepted the connection from
+    pub source: SocketAddr,
+    // the address the client connected to on the proxy
+    pub destination: SocketAddr,
+}
+
+// This function parses the PROXY protocol header at the start of what was received on a
+// connection. It returns `None` while the header is not complete yet, otherwise the header (which
+// carries no addresses for connections the proxy made on its own, e.g. health checks) and the
+// number of bytes it took up. As the protocol demands, a connection not starting with a header is
+// never taken for one without
+pub(crate) fn parse(
+    input: &[u8],
+) -> Result<Option<(Option<ProxyHeader>, usize)>, error::ProxyProtocolError> {
+    if input.starts_with(V1_PREFIX) {
+        return parse_v1(input);
+    }
+    if input.starts_with(V2_SIGNATURE) {
+        return parse_v2(input);
+    }
+    if V1_PREFIX.starts_with(input) || V2_SIGNATURE.starts_with(input) {
+        return Ok(None);
+    }
+    return Err(error::ProxyProtocolError::MissingHeaderError);
+}
+
+// This function reads the PROXY protocol header off the start of a connection, see `parse`. A
+// connection closed before sending anything gets no header, reading the request from it then
+// finds the connection closed too
+pub(crate) fn read_header<R: BufRead>(
+    reader: &mut R,
+) -> Result<Option<ProxyHeader>, error::ProxyProtocolError> {
+    let mut received = Vec::new();
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            if received.is_empty() {
+                return Ok(None);
+            }
+            return Err(error::ProxyProtocolError::InvalidHeaderError(
+                "the connection was closed in the middle of the header".to_string(),
+            ));
+        }
+        let length = available.len();
+        received.extend_from_slice(available);
+        match parse(&received)? {
+            Some((header, consumed)) => {
+                // what was received before is all part of the header and already consumed,
+                // everything in the reader past the header belongs to the first request
+                reader.consume(consumed - (received.len() - length));
+                return Ok(header);
+            }
+            None => reader.consume(length),
+        };
+    }
+}
+
+// e.g. `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n`, or `PROXY UNKNOWN\r\n` for
+// connections the proxy can not tell the addresses of
+fn parse_v1(
+    input: &[u8],
+) -> Result<Option<(Option<ProxyHeader>, usize)>, error::ProxyProtocolError> {
+    let end = match input
+        .iter()
+        .take(V1_MAX_LENGTH)
+        .position(|byte| *byte == b'\n')
+    {
+        Some(end) => end,
+        None if input.len() < V1_MAX_LENGTH => return Ok(None),
+        None => {
+            return Err(error::ProxyProtocolError::InvalidHeaderError(format!(
+                "version 1 header is longer than {} bytes",
+                V1_MAX_LENGTH
+            )));
+        }
+    };
+    let line = match input[..end].strip_suffix(b"\r") {
+        Some(line) => line,
+        None => {
+            return Err(error::ProxyProtocolError::InvalidHeaderError(
+                "version 1 header does not end with CRLF".to_string(),
+            ));
+        }
+    };
+    let line = match std::str::from_utf8(line) {
+        Ok(line) => line,
+        Err(_) => {
+            return Err(error::ProxyProtocolError::InvalidHeaderError(
+                "version 1 header is not valid ASCII".to_string(),
+            ));
+        }
+    };
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    let header = match fields[1..] {
+        ["UNKNOWN", ..] => None,
+        ["TCP4", source, destination, source_port, destination_port] => Some(ProxyHeader {
+            source: v1_address::<Ipv4Addr>(source, source_port)?,
+            destination: v1_address::<Ipv4Addr>(destination, destination_port)?,
+        }),
+        ["TCP6", source, destination, source_port, destination_port] => Some(ProxyHeader {
+            source: v1_address::<Ipv6Addr>(source, source_port)?,
+            destination: v1_address::<Ipv6Addr>(destination, destination_port)?,
+        }),
+        _ => {
+            return Err(error::ProxyProtocolError::InvalidHeaderError(format!(
+                "malformed version 1 header: {}",
+                line
+            )));
+        }
+    };
+    return Ok(Some((header, end + 1)));
+}
+
+fn v1_address<A>(address: &str, port: &str) -> Result<SocketAddr, error::ProxyProtocolError>
+where
+    A: std::str::FromStr + Into<IpAddr>,
+{
+    let address = match address.parse::<A>() {
+        Ok(address) => address,
+        Err(_) => {
+            return Err(error::ProxyProtocolError::InvalidHeaderError(format!(
+                "invalid address: {}",
+                address
+            )));
+        }
+    };
+    let port = match port.parse::<u16>() {
+        Ok(port) => port,
+        _ => {
+            return Err(error::ProxyProtocolError::InvalidHeaderError(format!(
+                "invalid port: {}",
+                port
+            )));
+        }
+    };
+    return Ok(SocketAddr::new(address.into(), port));
+}
+
+// the signature is followed by the version and command, the address family and transport
+// protocol, the length of the rest of the header and then the addresses (and optional extensions)
+fn parse_v2(
+    input: &[u8],
+) -> Result<Option<(Option<ProxyHeader>, usize)>, error::ProxyProtocolError> {
+    if input.len() < V2_HEADER_LENGTH {
+        return Ok(None);
+    }
+    let length = V2_HEADER_LENGTH + u16::from_be_bytes([input[14], input[15]]) as usize;
+    if input.len() < length {
+        return Ok(None);
+    }
+    let addresses = &input[V2_HEADER_LENGTH..length];
+
+    if input[12] >> 4 != 2 {
+        return Err(error::ProxyProtocolError::InvalidHeaderError(format!(
+            "unsupported version {}",
+            input[12] >> 4
+        )));
+    }
+    let header = match input[12] & 0x0F {
+        // LOCAL, a connection the proxy made on its own
+        0x0 => None,
+        // PROXY, only TCP over IPv4 and IPv6 carries addresses the server can use
+        0x1 => match (input[13], addresses.len()) {
+            (0x11, 12..) => Some(ProxyHeader {
+                source: SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::new(
+                        addresses[0],
+                        addresses[1],
+                        addresses[2],
+                        addresses[3],
+                    )),
+                    u16::from_be_bytes([addresses[8], addresses[9]]),
+                ),
+                destination: SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::new(
+                        addresses[4],
+                        addresses[5],
+                        addresses[6],
+                        addresses[7],
+                    )),
+                    u16::from_be_bytes([addresses[10], addresses[11]]),
+                ),
+            }),
+            (0x21, 36..) => {
+                let mut source = [0; 16];
+                let mut destination = [0; 16];
+                source.copy_from_slice(&addresses[0..16]);
+                destination.copy_from_slice(&addresses[16..32]);
+                Some(ProxyHeader {
+                    source: SocketAddr::new(
+                        IpAddr::V6(Ipv6Addr::from(source)),
+                        u16::from_be_bytes([addresses[32], addresses[33]]),
+                    ),
+                    destination: SocketAddr::new(
+                        IpAddr::V6(Ipv6Addr::from(destination)),
+                        u16::from_be_bytes([addresses[34], addresses[35]]),
+                    ),
+                })
+            }
+            (0x11, _) | (0x21, _) => {
+                return Err(error::ProxyProtocolError::InvalidHeaderError(
+                    "version 2 header is too short for its addresses".to_string(),
+                ));
+            }
+            _ => None,
+        },
+        command => {
+            return Err(error::ProxyProtocolError::InvalidHeaderError(format!(
+                "unsupported command {}",
+                command
+            )));
+        }
+    };
+    return Ok(Some((header, length)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Read};
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+
+    // This function builds a version 2 header with the given version and command, address family
+    // and what follows them (addresses and TLVs)
+    fn v2_header(version_command: u8, family: u8, rest: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(version_command);
+        header.push(family);
+        header.extend_from_slice(&(rest.len() as u16).to_be_bytes());
+        header.extend_from_slice(rest);
+        return header;
+    }
+
+    // the addresses of a TCP over IPv4 connection from 192.168.0.1:56324 to 192.168.0.11:443
+    fn v2_tcp4_addresses() -> Vec<u8> {
+        let mut addresses = vec![192, 168, 0, 1, 192, 168, 0, 11];
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        return addresses;
+    }
+
+    // This function parses `header` followed by a request, the header is expected to take up
+    // exactly its own bytes
+    fn parse_complete(header: &[u8]) -> Option<ProxyHeader> {
+        let input = [header, REQUEST].concat();
+        let (proxy_header, consumed) = parse(&input).unwrap().unwrap();
+        assert_eq!(consumed, header.len());
+        return proxy_header;
+    }
+
+    fn tcp4_header() -> ProxyHeader {
+        return ProxyHeader {
+            source: "192.168.0.1:56324".parse().unwrap(),
+            destination: "192.168.0.11:443".parse().unwrap(),
+        };
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        assert_eq!(
+            parse_complete(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n"),
+            Some(tcp4_header())
+        );
+        assert_eq!(
+            parse_complete(b"PROXY TCP6 2001:db8::1 2001:db8::11 56324 443\r\n"),
+            Some(ProxyHeader {
+                source: "[2001:db8::1]:56324".parse().unwrap(),
+                destination: "[2001:db8::11]:443".parse().unwrap(),
+            })
+        );
+        assert_eq!(parse_complete(b"PROXY UNKNOWN\r\n"), None);
+        // whatever follows `UNKNOWN` is ignored
+        assert_eq!(
+            parse_complete(b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_v1_headers() {
+        let too_long = format!("PROXY UNKNOWN {}\r\n", "a".repeat(V1_MAX_LENGTH));
+        for header in [
+            "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\n",
+            "PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n",
+            "PROXY TCP4 2001:db8::1 2001:db8::11 56324 443\r\n",
+            "PROXY TCP6 192.168.0.1 192.168.0.11 56324 443\r\n",
+            "PROXY TCP4 192.168.0.1 192.168.0.11 56324 65536\r\n",
+            "PROXY UDP4 192.168.0.1 192.168.0.11 56324 443\r\n",
+            "PROXY \r\n",
+            too_long.as_str(),
+        ] {
+            match parse(header.as_bytes()) {
+                Err(error::ProxyProtocolError::InvalidHeaderError(_)) => {}
+                other => panic!("{:?} was not rejected: {:?}", header, other),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_v2_headers() {
+        // a connection the proxy made on its own carries no addresses
+        assert_eq!(parse_complete(&v2_header(0x20, 0x00, b"")), None);
+        assert_eq!(
+            parse_complete(&v2_header(0x20, 0x11, &v2_tcp4_addresses())),
+            None
+        );
+        assert_eq!(
+            parse_complete(&v2_header(0x21, 0x11, &v2_tcp4_addresses())),
+            Some(tcp4_header())
+        );
+
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&"2001:db8::11".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(
+            parse_complete(&v2_header(0x21, 0x21, &addresses)),
+            Some(ProxyHeader {
+                source: "[2001:db8::1]:56324".parse().unwrap(),
+                destination: "[2001:db8::11]:443".parse().unwrap(),
+            })
+        );
+
+        // UDP and Unix domain socket addresses are of no use to the server
+        assert_eq!(
+            parse_complete(&v2_header(0x21, 0x12, &v2_tcp4_addresses())),
+            None
+        );
+        assert_eq!(parse_complete(&v2_header(0x21, 0x31, &[0; 216])), None);
+    }
+
+    #[test]
+    fn skips_the_tlvs_of_a_v2_header() {
+        let mut rest = v2_tcp4_addresses();
+        // a PP2_TYPE_AUTHORITY and a PP2_TYPE_NOOP
+        rest.extend_from_slice(&[0x02, 0x00, 0x0b]);
+        rest.extend_from_slice(b"example.com");
+        rest.extend_from_slice(&[0x04, 0x00, 0x00]);
+        assert_eq!(
+            parse_complete(&v2_header(0x21, 0x11, &rest)),
+            Some(tcp4_header())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_v2_headers() {
+        for header in [
+            // version 1 and 3 in the binary format
+            v2_header(0x11, 0x11, &v2_tcp4_addresses()),
+            v2_header(0x31, 0x11, &v2_tcp4_addresses()),
+            // an unknown command
+            v2_header(0x22, 0x11, &v2_tcp4_addresses()),
+            // addresses cut short by the length of the header
+            v2_header(0x21, 0x11, &v2_tcp4_addresses()[..11]),
+            v2_header(0x21, 0x21, &[0; 35]),
+        ] {
+            match parse(&header) {
+                Err(error::ProxyProtocolError::InvalidHeaderError(_)) => {}
+                other => panic!("{:?} was not rejected: {:?}", header, other),
+            }
+        }
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_truncated_header() {
+        for header in [
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n".to_vec(),
+            v2_header(0x21, 0x11, &v2_tcp4_addresses()),
+        ] {
+            for end in 0..header.len() {
+                assert_eq!(parse(&header[..end]).unwrap(), None, "{:?}", &header[..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_connection_without_a_header() {
+        for input in [REQUEST, b"PROXX", b"\r\n\r\n\0\r\nQUIX"] {
+            assert!(matches!(
+                parse(input),
+                Err(error::ProxyProtocolError::MissingHeaderError)
+            ));
+        }
+    }
+
+    #[test]
+    fn reads_the_header_off_a_connection() {
+        // the header arrives over several reads, the request after it is left in the reader
+        for header in [
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n".to_vec(),
+            v2_header(0x21, 0x11, &v2_tcp4_addresses()),
+        ] {
+            let input = [&header[..], REQUEST].concat();
+            let mut reader = BufReader::with_capacity(5, &input[..]);
+            assert_eq!(read_header(&mut reader).unwrap(), Some(tcp4_header()));
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, REQUEST);
+        }
+    }
+
+    #[test]
+    fn fails_on_a_connection_closed_in_the_middle_of_the_header() {
+        assert_eq!(read_header(&mut &b""[..]).unwrap(), None);
+        assert!(matches!(
+            read_header(&mut &b"PROXY TCP4 192.168.0.1"[..]),
+            Err(error::ProxyProtocolError::InvalidHeaderError(_))
+        ));
+    }
+}
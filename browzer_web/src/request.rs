@@ -1,5 +1,10 @@
-use crate::{error, utils};
-use std::collections::HashMap;
+use crate::{config, connection, error, proxy_protocol, shutdown, utils};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead},
+    net::{IpAddr, Ipv6Addr},
+    sync::Arc,
+};
 
 #[derive(Debug)]
 pub struct Request {
@@ -7,8 +12,31 @@ pub struct Request {
     pub path: String,
     pub version: String,
     pub headers: HashMap<String, String>,
-    pub body: Option<String>,
+    pub body: Option<Vec<u8>>,
+    pub trailers: HashMap<String, String>,
     pub cookies: HashMap<String, utils::Cookie>,
+    // the negotiated TLS parameters if the request arrived over an encrypted connection
+    pub tls: Option<connection::TlsInfo>,
+    // the addresses of the client's and the server's end of the connection the request arrived
+    // on, see `remote_addr` and `local_addr`
+    pub(crate) remote_addr: Option<connection::SocketAddr>,
+    pub(crate) local_addr: Option<connection::SocketAddr>,
+    // the address of the client the request came from, see `client_ip`
+    pub(crate) client_ip: Option<IpAddr>,
+    // the addresses of the client's connection to the load balancer the request came through, if
+    // it relayed them with the PROXY protocol, see `ServerConfig::proxy_protocol`
+    pub proxy: Option<proxy_protocol::ProxyHeader>,
+    // the shutdown state of the server the request arrived at, a response streaming for long
+    // (e.g. `Context::send_events`) ends once a shutdown is requested
+    pub(crate) shutdown: Option<Arc<shutdown::ShutdownState>>,
+}
+
+// How the body of a request is delimited on the wire
+#[derive(Debug, Clone, PartialEq)]
+pub enum BodyFraming {
+    Empty,
+    ContentLength(usize),
+    Chunked,
 }
 impl Default for Request {
     fn default() -> Self {
@@ -18,7 +46,14 @@ impl Default for Request {
             version: String::from("HTTP/1.1"),
             headers: HashMap::new(),
             body: None,
+            trailers: HashMap::new(),
             cookies: HashMap::new(),
+            tls: None,
+            remote_addr: None,
+            local_addr: None,
+            client_ip: None,
+            proxy: None,
+            shutdown: None,
         }
     }
 }
@@ -38,12 +73,13 @@ impl Request {
             Some(request_line) => {
                 let parts: Vec<_> = request_line.split_whitespace().collect();
                 if parts.len() >= 3 {
-                    method = match parts[0] {
-                        "GET" => utils::HttpMethod::GET,
-                        "POST" => utils::HttpMethod::POST,
-                        "PATCH" => utils::HttpMethod::PATCH,
-                        "DELETE" => utils::HttpMethod::DELETE,
-                        _ => utils::HttpMethod::GET,
+                    method = match utils::HttpMethod::parse(parts[0]) {
+                        Some(method) => method,
+                        None => {
+                            return Err(error::RequestError::InvalidRequestLineError(
+                                request_line.to_string(),
+                            ));
+                        }
                     };
                     path = parts[1].to_string();
                     version = parts[2].to_string();
@@ -65,13 +101,36 @@ impl Request {
             if curr_line.trim().is_empty() {
                 break;
             }
-            let parts: Vec<_> = curr_line.splitn(2, ":").map(|s| s.trim()).collect();
+            let parts: Vec<_> = curr_line.splitn(2, ":").collect();
             if parts.len() == 2 {
-                headers.insert(parts[0].to_string(), parts[1].to_string());
+                let (name, value) = (parts[0], parts[1].trim());
+                // a proxy in front of us may not take `Transfer-Encoding : chunked` for the
+                // header it would be here (request smuggling), so whitespace around the field name
+                // is not allowed
+                if name.is_empty() || name.trim().len() != name.len() {
+                    return Err(error::RequestError::InvalidHeaderError(
+                        curr_line.to_string(),
+                    ));
+                }
+                // only one of several framing headers would be kept, while the proxy might have
+                // gone by another one
+                if ["Content-Length", "Transfer-Encoding"]
+                    .iter()
+                    .any(|framing| name.eq_ignore_ascii_case(framing))
+                    && headers
+                        .keys()
+                        .any(|key: &String| key.eq_ignore_ascii_case(name))
+                {
+                    return Err(error::RequestError::InvalidHeaderError(format!(
+                        "more than one {} header",
+                        name
+                    )));
+                }
+                headers.insert(name.to_string(), value.to_string());
             }
             index += 1;
         }
-        // parse body into a string by looping over the remaining input string vector elements and
+        // parse body into bytes by looping over the remaining input string vector elements and
         // joining them using the newline operator
         let body = if index + 1 < input.len() {
             Some(
@@ -79,7 +138,8 @@ impl Request {
                     .iter()
                     .map(|s| &**s) // NOTE: I have NO idea what is happening here
                     .collect::<Vec<_>>()
-                    .join("\n"),
+                    .join("\n")
+                    .into_bytes(),
             )
         } else {
             None
@@ -103,7 +163,499 @@ impl Request {
             version,
             headers,
             body,
+            trailers: HashMap::new(),
             cookies,
+            tls: None,
+            remote_addr: None,
+            local_addr: None,
+            client_ip: None,
+            proxy: None,
+            shutdown: None,
         });
     }
+
+    // This function reads a single HTTP request off a buffered reader (usually wrapping the
+    // connection stream) and parses it into a `Request` struct. Reading stops right after the
+    // body so that any bytes following this request stay in the reader for the next one.
+    //
+    // `Ok(None)` is returned when the reader hits EOF before a single byte of a new request was
+    // read, which is how a client cleanly closes a persistent connection. A request going over
+    // any of the `limits` is rejected with an error.
+    pub fn read_from<R: BufRead>(
+        reader: &mut R,
+        limits: &config::RequestLimits,
+    ) -> Result<Option<Request>, error::WebServerError> {
+        let mut request = match Request::read_head(reader, limits) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match request.read_body(reader, limits) {
+            Ok(_) => Ok(Some(request)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // This function reads the request line and the headers up to (and including) the empty line
+    // that ends them, and parses them into a `Request` struct without a body
+    pub fn read_head<R: BufRead>(
+        reader: &mut R,
+        limits: &config::RequestLimits,
+    ) -> Result<Option<Request>, error::WebServerError> {
+        let mut request_vector = Vec::new();
+        let mut header_size = 0;
+        loop {
+            // the request line and the header fields are bounded separately, every header line
+            // may only use up what is left of the `max_header_size` budget, the empty line which
+            // ends the headers is not charged against it so it is always given room for a CRLF
+            let line = match request_vector.is_empty() {
+                true => utils::read_line(
+                    reader,
+                    limits.max_request_line_length,
+                    error::RequestError::RequestLineTooLongError(limits.max_request_line_length),
+                ),
+                false => utils::read_line(
+                    reader,
+                    limits.max_header_size.saturating_sub(header_size) + 2,
+                    error::RequestError::HeaderFieldsTooLargeError(format!(
+                        "more than {} bytes",
+                        limits.max_header_size
+                    )),
+                ),
+            };
+            match line {
+                Ok(Some(line)) => {
+                    if line.is_empty() {
+                        break;
+                    }
+                    if !request_vector.is_empty() {
+                        header_size += line.len() + 2;
+                        if header_size > limits.max_header_size {
+                            return Err(error::WebServerError::RequestParseError(
+                                error::RequestError::HeaderFieldsTooLargeError(format!(
+                                    "more than {} bytes",
+                                    limits.max_header_size
+                                )),
+                            ));
+                        }
+                        if request_vector.len() > limits.max_header_count {
+                            return Err(error::WebServerError::RequestParseError(
+                                error::RequestError::HeaderFieldsTooLargeError(format!(
+                                    "more than {} header fields",
+                                    limits.max_header_count
+                                )),
+                            ));
+                        }
+                    }
+                    request_vector.push(line);
+                }
+                Ok(None) => {
+                    if request_vector.is_empty() {
+                        return Ok(None);
+                    }
+                    return Err(error::WebServerError::IO(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed in the middle of the request head",
+                    )));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        match Request::new(&request_vector) {
+            Ok(request) => Ok(Some(request)),
+            Err(e) => Err(error::WebServerError::RequestParseError(e)),
+        }
+    }
+
+    // This function works out how the body of the request is delimited on the wire from its
+    // `Content-Length` and `Transfer-Encoding` headers. A request carrying both of them is
+    // rejected, as a proxy in front of us might have picked the other one and we would disagree
+    // on where this request ends (request smuggling)
+    pub fn body_framing(&self) -> Result<BodyFraming, error::RequestError> {
+        match (
+            self.header("Content-Length"),
+            self.header("Transfer-Encoding"),
+        ) {
+            (Some(_), Some(_)) => Err(error::RequestError::ConflictingFramingError),
+            (None, Some(transfer_encoding)) => {
+                match transfer_encoding.trim().eq_ignore_ascii_case("chunked") {
+                    true => Ok(BodyFraming::Chunked),
+                    false => Err(error::RequestError::UnsupportedTransferEncodingError(
+                        transfer_encoding.to_string(),
+                    )),
+                }
+            }
+            // the length is digits only, `parse` would take a leading `+` too
+            (Some(content_length), None) => {
+                match content_length.bytes().all(|b| b.is_ascii_digit()) {
+                    true => match content_length.parse::<usize>() {
+                        Ok(0) => Ok(BodyFraming::Empty),
+                        Ok(length) => Ok(BodyFraming::ContentLength(length)),
+                        Err(_) => Err(error::RequestError::InvalidContentLengthError(
+                            content_length.to_string(),
+                        )),
+                    },
+                    false => Err(error::RequestError::InvalidContentLengthError(
+                        content_length.to_string(),
+                    )),
+                }
+            }
+            (None, None) => Ok(BodyFraming::Empty),
+        }
+    }
+
+    // This function reads the body of the request off the reader according to its
+    // `body_framing`, a chunked body is decoded and its trailer fields are stored in `trailers`.
+    // A body larger than `max_body_size` is rejected before it is read
+    pub fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        limits: &config::RequestLimits,
+    ) -> Result<(), error::WebServerError> {
+        let body = match self.body_framing() {
+            Ok(BodyFraming::Empty) => return Ok(()),
+            Ok(BodyFraming::ContentLength(content_length)) => {
+                if content_length > limits.max_body_size {
+                    return Err(error::WebServerError::RequestParseError(
+                        error::RequestError::PayloadTooLargeError(limits.max_body_size),
+                    ));
+                }
+                let mut body = vec![0; content_length];
+                match reader.read_exact(&mut body) {
+                    Ok(_) => {}
+                    Err(e) => return Err(error::WebServerError::IO(e)),
+                }
+                body
+            }
+            Ok(BodyFraming::Chunked) => match utils::chunked::decode(reader, limits) {
+                Ok((body, trailers)) => {
+                    self.trailers = trailers;
+                    body
+                }
+                Err(e) => return Err(e),
+            },
+            Err(e) => return Err(error::WebServerError::RequestParseError(e)),
+        };
+        if !body.is_empty() {
+            self.body = Some(body);
+        }
+        return Ok(());
+    }
+
+    // This function returns the body as text, `None` is returned if there is no body or if it is
+    // not valid UTF-8
+    pub fn body_str(&self) -> Option<&str> {
+        match &self.body {
+            Some(body) => std::str::from_utf8(body).ok(),
+            None => None,
+        }
+    }
+
+    // This function returns the body as text with any invalid UTF-8 sequences replaced by
+    // `U+FFFD`, an empty string is returned if there is no body
+    pub fn body_text(&self) -> String {
+        match &self.body {
+            Some(body) => String::from_utf8_lossy(body).to_string(),
+            None => String::new(),
+        }
+    }
+
+    // This function returns the address of the peer of the connection the request arrived on, for
+    // requests coming through a proxy (or load balancer) that is the proxy, see `client_ip`
+    pub fn remote_addr(&self) -> Option<&connection::SocketAddr> {
+        return self.remote_addr.as_ref();
+    }
+
+    // This function returns the address of the server's end of the connection the request arrived
+    // on, which tells the listeners of a server bound to several addresses apart
+    pub fn local_addr(&self) -> Option<&connection::SocketAddr> {
+        return self.local_addr.as_ref();
+    }
+
+    // This function returns the IP address of the client the request came from. It is the
+    // address of the peer of the connection (or the one relayed with the PROXY protocol), unless
+    // that is one of the `trusted_proxies` of the `ServerConfig`. Then the client is the last
+    // address in the `Forwarded` (or `X-Forwarded-For`) header not belonging to a trusted proxy,
+    // as only the entries added by trusted proxies can be relied upon. It is `None` for requests
+    // over a Unix domain socket which do not name a client
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        return self.client_ip;
+    }
+
+    // This function finds the address `client_ip` returns once the connection the request
+    // arrived on is known
+    pub(crate) fn resolve_client_ip(&mut self, trusted_proxies: &[config::IpCidr]) {
+        let trusted = |address: &IpAddr| trusted_proxies.iter().any(|cidr| cidr.contains(address));
+        let mut client_ip = match (&self.proxy, &self.remote_addr) {
+            (Some(proxy), _) => Some(proxy.source.ip().to_canonical()),
+            (None, Some(connection::SocketAddr::Tcp(address))) => Some(address.ip().to_canonical()),
+            // the peer of a Unix domain socket is a local process, e.g. a reverse proxy
+            #[cfg(unix)]
+            (None, Some(connection::SocketAddr::Unix(_))) => None,
+            (None, None) => {
+                self.client_ip = None;
+                return;
+            }
+        };
+        if let Some(address) = &client_ip {
+            if !trusted(address) {
+                self.client_ip = client_ip;
+                return;
+            }
+        }
+
+        // every proxy appends the address it got the request from, so the list is walked back
+        // from the proxy closest to the server until an address is not a trusted proxy. An entry
+        // which is not an address (e.g. `unknown`) ends the walk at the proxy that added it
+        for address in self.forwarded_for().into_iter().rev() {
+            match address {
+                Some(address) => {
+                    client_ip = Some(address);
+                    if !trusted(&address) {
+                        break;
+                    }
+                }
+                None => break,
+            };
+        }
+        self.client_ip = client_ip;
+    }
+
+    // This function lists the addresses the request was forwarded for, from the client to the
+    // last proxy, as named by the `for` parameters of the `Forwarded` header or by the
+    // `X-Forwarded-For` header if there is none
+    fn forwarded_for(&self) -> Vec<Option<IpAddr>> {
+        if let Some(forwarded) = self.header("Forwarded") {
+            return forwarded
+                .split(',')
+                .map(|element| {
+                    element
+                        .split(';')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                        .and_then(|(_, node)| parse_forwarded_node(node))
+                })
+                .collect();
+        }
+        match self.header("X-Forwarded-For") {
+            Some(forwarded_for) => {
+                return forwarded_for.split(',').map(parse_forwarded_node).collect();
+            }
+            None => return Vec::new(),
+        }
+    }
+
+    // This function looks up a header by name ignoring ASCII case, as header names in HTTP are
+    // case-insensitive and clients do not agree on a single spelling
+    pub fn header(&self, name: &str) -> Option<&String> {
+        return self
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value);
+    }
+
+    // This function decides whether the client wants the connection to stay open after this
+    // request, HTTP/1.1 connections are persistent unless the client sends `Connection: close`
+    // while HTTP/1.0 connections are closed unless the client sends `Connection: keep-alive`
+    pub fn keep_alive(&self) -> bool {
+        let connection_has = |token: &str| match self.header("Connection") {
+            Some(value) => value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token)),
+            None => false,
+        };
+        match self.version.as_str() {
+            "HTTP/1.1" => !connection_has("close"),
+            _ => connection_has("keep-alive"),
+        }
+    }
+
+    // This function returns whether the client waits for `100 Continue` before sending the body,
+    // an expectation other than `100-continue` can not be met and is an error. Clients older than
+    // HTTP/1.1 do not know about interim responses, their `Expect` header is ignored
+    pub fn expects_continue(&self) -> Result<bool, error::RequestError> {
+        if self.version == "HTTP/1.0" {
+            return Ok(false);
+        }
+        match self.header("Expect") {
+            Some(value) if value.trim().eq_ignore_ascii_case("100-continue") => return Ok(true),
+            Some(value) => {
+                return Err(error::RequestError::UnsupportedExpectationError(
+                    value.to_string(),
+                ));
+            }
+            None => return Ok(false),
+        }
+    }
+}
+
+// This function parses the IP address out of a node of the `Forwarded` or `X-Forwarded-For`
+// header, e.g. `192.0.2.43`, `192.0.2.43:47011`, `2001:db8::17` or `"[2001:db8::17]:47011"`
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(node) = node.strip_prefix('[') {
+        return match node.split_once(']') {
+            Some((address, _)) => address
+                .parse::<Ipv6Addr>()
+                .ok()
+                .map(|address| IpAddr::V6(address).to_canonical()),
+            None => None,
+        };
+    }
+    if let Ok(address) = node.parse::<IpAddr>() {
+        return Some(address.to_canonical());
+    }
+    return match node.rsplit_once(':') {
+        Some((address, _)) => address
+            .parse::<IpAddr>()
+            .ok()
+            .map(|address| address.to_canonical()),
+        None => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This function parses the head made of `lines`
+    fn parse(lines: &[&str]) -> Result<Request, error::RequestError> {
+        let input: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        return Request::new(&input);
+    }
+
+    #[test]
+    fn trims_the_value_of_a_header() {
+        let request = parse(&["GET / HTTP/1.1", "Host:  localhost \t"]).unwrap();
+        assert_eq!(request.header("host").unwrap(), "localhost");
+    }
+
+    #[test]
+    fn rejects_whitespace_around_a_header_name() {
+        for line in [
+            "Transfer-Encoding : chunked",
+            "Transfer-Encoding\t: chunked",
+            " Transfer-Encoding: chunked",
+            ": chunked",
+        ] {
+            match parse(&["POST / HTTP/1.1", "Host: localhost", line]) {
+                Err(error::RequestError::InvalidHeaderError(_)) => {}
+                other => panic!("{:?} was not rejected: {:?}", line, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_repeated_framing_headers() {
+        for lines in [
+            ["Content-Length: 5", "content-length: 5"],
+            ["Transfer-Encoding: chunked", "transfer-encoding: chunked"],
+        ] {
+            let error = parse(&["POST / HTTP/1.1", lines[0], lines[1]])
+                .err()
+                .unwrap();
+            assert!(
+                matches!(error, error::RequestError::InvalidHeaderError(_)),
+                "{:?}",
+                error
+            );
+            assert_eq!(
+                error.status_code().code(),
+                utils::HttpStatusCode::BadRequest.code()
+            );
+        }
+    }
+
+    // small limits to go over in the tests below
+    fn limits() -> config::RequestLimits {
+        return config::RequestLimits {
+            max_request_line_length: 32,
+            max_header_count: 3,
+            max_header_size: 64,
+            max_body_size: 16,
+        };
+    }
+
+    // This function reads the request in `input` off a reader and returns the status code it is
+    // turned down with, or `None` if it is read
+    fn turned_down_with(input: &str) -> Option<u16> {
+        match Request::read_from(&mut input.as_bytes(), &limits()) {
+            Ok(Some(_)) => None,
+            Ok(None) => panic!("no request in {:?}", input),
+            Err(error::WebServerError::RequestParseError(e)) => Some(e.status_code().code().1),
+            Err(e) => panic!("{:?} failed to be read: {}", input, e),
+        }
+    }
+
+    #[test]
+    fn answers_a_request_line_over_the_limit_with_414() {
+        // the request line takes up 32 bytes with its line break
+        let at_limit = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(16));
+        assert_eq!(turned_down_with(&at_limit), None);
+        let over_limit = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(17));
+        assert_eq!(turned_down_with(&over_limit), Some(414));
+    }
+
+    #[test]
+    fn answers_header_fields_over_the_limit_with_431() {
+        // the header fields take up 64 bytes with their line breaks
+        let at_limit = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Filler: {}\r\n\r\n",
+            "a".repeat(35)
+        );
+        assert_eq!(turned_down_with(&at_limit), None);
+        let over_limit = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Filler: {}\r\n\r\n",
+            "a".repeat(36)
+        );
+        assert_eq!(turned_down_with(&over_limit), Some(431));
+    }
+
+    #[test]
+    fn answers_more_header_fields_than_the_limit_with_431() {
+        assert_eq!(
+            turned_down_with("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            turned_down_with("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\n"),
+            Some(431)
+        );
+    }
+
+    #[test]
+    fn answers_a_body_over_the_limit_with_413() {
+        let at_limit = format!(
+            "POST / HTTP/1.1\r\nContent-Length: 16\r\n\r\n{}",
+            "a".repeat(16)
+        );
+        assert_eq!(turned_down_with(&at_limit), None);
+        // the body is turned down before it is read
+        assert_eq!(
+            turned_down_with("POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n"),
+            Some(413)
+        );
+    }
+
+    #[test]
+    fn answers_content_length_along_with_transfer_encoding_with_400() {
+        assert_eq!(
+            turned_down_with(
+                "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"
+            ),
+            Some(400)
+        );
+        let request = parse(&[
+            "POST / HTTP/1.1",
+            "Transfer-Encoding: chunked",
+            "content-length: 5",
+        ])
+        .unwrap();
+        assert!(matches!(
+            request.body_framing(),
+            Err(error::RequestError::ConflictingFramingError)
+        ));
+    }
 }
@@ -1,13 +1,194 @@
+use crate::{connection, utils};
 use chrono;
-use crate::utils;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+type Chunks = Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>;
+pub(crate) type UpgradeHandler = Box<dyn FnOnce(connection::Upgraded) + Send>;
+
+// The interim response telling a client which sent `Expect: 100-continue` to go on with the body
+pub(crate) const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+// The body of a response, either held entirely in memory as raw bytes or produced incrementally
+// by a `StreamBody` while the response is being written to the client
+#[derive(Debug, Clone)]
+pub enum Body {
+    Full(Vec<u8>),
+    Stream(StreamBody),
+}
+
+impl Body {
+    // This function returns the body as text if it is held in memory and is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Body::Full(bytes) => std::str::from_utf8(bytes).ok(),
+            Body::Stream(_) => None,
+        }
+    }
+
+    // This function returns the body if it is held in memory
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Full(bytes) => Some(bytes),
+            Body::Stream(_) => None,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(body: Vec<u8>) -> Self {
+        return Body::Full(body);
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(body: &[u8]) -> Self {
+        return Body::Full(body.to_vec());
+    }
+}
+
+impl From<String> for Body {
+    fn from(body: String) -> Self {
+        return Body::Full(body.into_bytes());
+    }
+}
+
+impl From<&str> for Body {
+    fn from(body: &str) -> Self {
+        return Body::Full(body.as_bytes().to_vec());
+    }
+}
+
+impl From<StreamBody> for Body {
+    fn from(body: StreamBody) -> Self {
+        return Body::Stream(body);
+    }
+}
+
+// A response body backed by an iterator of chunks (or a `Read` source), it is written with
+// `Transfer-Encoding: chunked` so the full body never has to be buffered in memory.
+//
+// The source can only be consumed once, clones of a `StreamBody` share the same source
+#[derive(Clone)]
+pub struct StreamBody {
+    chunks: Arc<Mutex<Option<Chunks>>>,
+}
+
+impl fmt::Debug for StreamBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamBody")
+            .field(
+                "chunks",
+                &"Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>",
+            )
+            .finish()
+    }
+}
+
+impl StreamBody {
+    // This function creates a streaming body out of anything that can be iterated over as chunks
+    // of bytes, every item is sent to the client as a single chunk as soon as it is produced
+    pub fn from_chunks<I, T>(chunks: I) -> StreamBody
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+        T: Into<Vec<u8>>,
+    {
+        return StreamBody::from_fallible_chunks(chunks.into_iter().map(|chunk| Ok(chunk.into())));
+    }
+
+    // This function creates a streaming body out of an iterator whose chunks can fail, an error
+    // aborts the response and closes the connection without the terminating chunk so the client
+    // can tell the body is incomplete
+    pub fn from_fallible_chunks<I>(chunks: I) -> StreamBody
+    where
+        I: Iterator<Item = io::Result<Vec<u8>>> + Send + 'static,
+    {
+        return StreamBody {
+            chunks: Arc::new(Mutex::new(Some(Box::new(chunks)))),
+        };
+    }
+
+    // This function creates a streaming body which copies a `Read` source to the client in chunks
+    // of up to 8 KiB
+    pub fn from_reader<R: Read + Send + 'static>(mut reader: R) -> StreamBody {
+        return StreamBody::from_fallible_chunks(std::iter::from_fn(move || {
+            let mut buf = vec![0; 8 * 1024];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => return None,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        return Some(Ok(buf));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        }));
+    }
+
+    // takes the source out of the body, `None` is returned if it was already consumed
+    pub(crate) fn take(&self) -> Option<Chunks> {
+        match self.chunks.lock() {
+            Ok(mut chunks) => chunks.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }
+    }
+}
+
+// What a connection is handed to once a `101 Switching Protocols` response was written to it, the
+// handler takes the connection over to speak another protocol on it (see `websocket`).
+//
+// The handler can only run once, clones of an `Upgrade` share the same handler
+#[derive(Clone)]
+pub struct Upgrade {
+    handler: Arc<Mutex<Option<UpgradeHandler>>>,
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upgrade")
+            .field("handler", &"Box<dyn FnOnce(connection::Upgraded) + Send>")
+            .finish()
+    }
+}
+
+impl Upgrade {
+    pub fn new<F>(handler: F) -> Upgrade
+    where
+        F: FnOnce(connection::Upgraded) + Send + 'static,
+    {
+        return Upgrade {
+            handler: Arc::new(Mutex::new(Some(Box::new(handler)))),
+        };
+    }
+
+    // takes the handler out, `None` is returned if it already ran
+    pub(crate) fn take(&self) -> Option<UpgradeHandler> {
+        match self.handler.lock() {
+            Ok(mut handler) => handler.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Response {
     pub status_code: utils::HttpStatusCode,
     pub headers: HashMap<String, String>,
-    pub body: String,
+    pub body: Body,
     pub cookies: HashMap<String, utils::Cookie>,
+    // when set only the status line and headers are written, as the answer to a `HEAD` request,
+    // `Content-Length` still tells the size of the body
+    pub omit_body: bool,
+    // when set the connection is handed over to it after the response is written, the response
+    // has to be a `101 Switching Protocols`
+    pub upgrade: Option<Upgrade>,
 }
 
 impl Default for Response {
@@ -15,35 +196,151 @@ impl Default for Response {
         return Response {
             status_code: utils::HttpStatusCode::OK,
             headers: HashMap::new(),
-            body: String::from(""),
+            body: Body::Full(Vec::new()),
             cookies: HashMap::new(),
+            omit_body: false,
+            upgrade: None,
         };
     }
 }
 
 impl Response {
-    pub fn new(status_code: utils::HttpStatusCode, body: String) -> Response {
+    pub fn new<B>(status_code: utils::HttpStatusCode, body: B) -> Response
+    where
+        B: Into<Body>,
+    {
+        return Response {
+            status_code,
+            headers: HashMap::new(),
+            body: body.into(),
+            cookies: HashMap::new(),
+            omit_body: false,
+            upgrade: None,
+        };
+    }
+
+    // This function creates a response whose body is streamed to the client, see `StreamBody`
+    pub fn stream(status_code: utils::HttpStatusCode, body: StreamBody) -> Response {
         return Response {
             status_code,
             headers: HashMap::new(),
-            body,
+            body: Body::Stream(body),
             cookies: HashMap::new(),
+            omit_body: false,
+            upgrade: None,
         };
     }
 
+    // This function looks up a header set on the response by name ignoring ASCII case
+    pub fn header(&self, name: &str) -> Option<&String> {
+        return self
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value);
+    }
+
+    // This function sets the `Connection` header according to whether the connection this
+    // response is written to will be kept open, a `Connection: close` set by the handler itself
+    // always wins and forces the connection to be closed. It returns the final decision.
+    pub fn set_keep_alive(&mut self, keep_alive: bool) -> bool {
+        // a response handing the connection over keeps its `Connection: Upgrade`, the connection
+        // is not used for HTTP anymore either way
+        if self.upgrade.is_some() {
+            return false;
+        }
+        let keep_alive = keep_alive
+            && !matches!(self.header("Connection"), Some(value) if value.trim().eq_ignore_ascii_case("close"));
+        self.headers
+            .retain(|key, _| !key.eq_ignore_ascii_case("Connection"));
+        self.headers.insert(
+            "Connection".to_string(),
+            match keep_alive {
+                true => "keep-alive",
+                false => "close",
+            }
+            .to_string(),
+        );
+        return keep_alive;
+    }
+
     // This function convert the `Response` struct into a string to be sent as bytes by setting the status_code
     // number, status_code text, and content-length in the `Status Line`, setting headers
     // to the response string by looping over `headers` field in the Response struct and looping
     // over the `cookies` field in the Response struct, and then finally adding a blank line
-    // followed by the body of the response to the response string
+    // followed by the body of the response to the response string.
+    //
+    // Bytes of the body which are not valid UTF-8 are replaced and a streaming body can not be
+    // turned into a string at all (only the head of such a response is returned), use `write_to`
+    // to send a response to the client
     pub fn to_string(&self) -> String {
+        let mut response = self.head_string(true);
+        if let Body::Full(body) = &self.body {
+            response.push_str(&String::from_utf8_lossy(body));
+        }
+        return response;
+    }
+
+    // This function writes the response to `writer` as raw bytes. A full body is written in one
+    // go after the head, while a streaming body is written chunk by chunk as the source produces them, with
+    // the writer flushed after every chunk.
+    //
+    // `chunked` tells whether the client understands `Transfer-Encoding: chunked` (HTTP/1.1), if
+    // it does not the streaming body is written as is and the end of the body is marked by the
+    // caller closing the connection
+    pub fn write_to<W: Write>(&self, writer: &mut W, chunked: bool) -> io::Result<()> {
+        if self.omit_body {
+            return writer.write_all(self.head_string(chunked).as_bytes());
+        }
+        match &self.body {
+            Body::Full(body) => {
+                writer.write_all(self.head_string(chunked).as_bytes())?;
+                writer.write_all(body)
+            }
+            Body::Stream(stream) => {
+                writer.write_all(self.head_string(chunked).as_bytes())?;
+                writer.flush()?;
+                if let Some(chunks) = stream.take() {
+                    for chunk in chunks {
+                        let chunk = chunk?;
+                        match chunked {
+                            true => utils::chunked::write_chunk(writer, &chunk)?,
+                            false => writer.write_all(&chunk)?,
+                        }
+                        writer.flush()?;
+                    }
+                }
+                if chunked {
+                    utils::chunked::write_last_chunk(writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // This function builds the status line and the headers of the response, the framing header is
+    // `Content-Length` for a full body and `Transfer-Encoding: chunked` for a streaming one (or
+    // none at all when the client does not support chunked encoding). A `204` or `304` response
+    // never has a body, so it is sent without any framing header
+    pub(crate) fn head_string(&self, chunked: bool) -> String {
         let status_code = &self.status_code.code();
-        let mut response = format!(
-            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
-            status_code.1,
-            status_code.0,
-            &self.body.len(),
-        );
+        let mut response = format!("HTTP/1.1 {} {}\r\n", status_code.1, status_code.0);
+        match &self.body {
+            // a response handing the connection over has no body
+            _ if self.upgrade.is_some() => {}
+            _ if matches!(
+                self.status_code,
+                utils::HttpStatusCode::NoContent | utils::HttpStatusCode::NotModified
+            ) => {}
+            Body::Full(body) => {
+                response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+            }
+            Body::Stream(_) => {
+                if chunked {
+                    response.push_str("Transfer-Encoding: chunked\r\n");
+                }
+            }
+        }
         for (key, value) in &self.headers {
             response.push_str(&format! {"{}: {}\r\n",key,value});
         }
@@ -82,7 +379,6 @@ impl Response {
         }
 
         response.push_str("\r\n");
-        response.push_str(&self.body);
         return response;
     }
 }
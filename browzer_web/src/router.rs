@@ -1,5 +1,13 @@
-use crate::{context, error, request, response, utils};
-use std::{collections::HashMap, fmt};
+use crate::{config, context, error, request, response, utils};
+use std::{
+    collections::HashMap,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+};
+
+type RouteHandler = Box<dyn Fn(context::Context) -> response::Response + 'static + Send + Sync>;
+type ExpectationHandler =
+    Box<dyn Fn(&request::Request) -> Option<response::Response> + 'static + Send + Sync>;
 
 pub struct WebRouter {
     // HashMap< --path-- ,HashMap< --method-- , RouteHandlerFunction>>
@@ -11,6 +19,19 @@ pub struct WebRouter {
         >,
     >,
     pub middlewares: Vec<Box<dyn Fn(context::Context) -> context::Context + 'static + Send + Sync>>,
+    // decides whether a request sending `Expect: 100-continue` gets to send its body
+    pub expectation_handler: Option<ExpectationHandler>,
+}
+
+// How a request is answered before its body is read
+pub(crate) enum Expectation {
+    // the client does not wait for an interim response, the body is read right away
+    None,
+    // the client waits for `100 Continue` before it sends the body
+    Continue,
+    // the request is turned down with this final response before the body is sent, the
+    // connection has to be closed afterwards since the body may still arrive
+    Reject(response::Response),
 }
 
 impl fmt::Debug for WebRouter {
@@ -18,6 +39,7 @@ impl fmt::Debug for WebRouter {
         f.debug_struct("WebRouter")
             .field("routes", &"HashMap<String, HashMap<String, Box<dyn Fn(context::Context) -> response::Response + Send + Sync + 'static>>>")
             .field("middlewares", &"Vec<Box<dyn Fn(context::Context) -> context::Context + 'static + Send + Sync>>")
+            .field("expectation_handler", &"Option<Box<dyn Fn(&request::Request) -> Option<response::Response> + 'static + Send + Sync>>")
             .finish()
     }
 }
@@ -27,6 +49,7 @@ impl WebRouter {
         return WebRouter {
             routes: HashMap::new(),
             middlewares: vec![],
+            expectation_handler: None,
         };
     }
 
@@ -59,6 +82,72 @@ impl WebRouter {
         self.middlewares.push(Box::new(middleware_func));
     }
 
+    pub fn set_expectation_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&request::Request) -> Option<response::Response> + 'static + Send + Sync,
+    {
+        self.expectation_handler = Some(Box::new(handler));
+    }
+
+    // This function decides how a request whose head was read is answered before its body is
+    // read. A request with `Expect: 100-continue` is turned down right away if its body is larger
+    // than `max_body_size` or the `expectation_handler` returns a response for it, otherwise the
+    // client is told to go on with `100 Continue`
+    pub(crate) fn check_expectation(
+        &self,
+        request: &request::Request,
+        limits: &config::RequestLimits,
+    ) -> Result<Expectation, error::WebServerError> {
+        match request.expects_continue() {
+            Ok(true) => {}
+            Ok(false) => return Ok(Expectation::None),
+            Err(e) => return Err(error::WebServerError::RequestParseError(e)),
+        };
+        match request.body_framing() {
+            Ok(request::BodyFraming::Empty) => return Ok(Expectation::None),
+            Ok(request::BodyFraming::ContentLength(content_length))
+                if content_length > limits.max_body_size =>
+            {
+                return Err(error::WebServerError::RequestParseError(
+                    error::RequestError::PayloadTooLargeError(limits.max_body_size),
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => return Err(error::WebServerError::RequestParseError(e)),
+        };
+        match &self.expectation_handler {
+            Some(handler) => match (handler)(request) {
+                Some(response) => return Ok(Expectation::Reject(response)),
+                None => return Ok(Expectation::Continue),
+            },
+            None => return Ok(Expectation::Continue),
+        }
+    }
+
+    // This function generates the response for a request, the response to a `HEAD` request is
+    // generated like the one for a `GET` request but only its status line and headers get written.
+    // A middleware or handler which panics gets the request answered with `500 Internal Server
+    // Error`, and the connection closed after it
+    pub fn handle_request(
+        &self,
+        request: request::Request,
+    ) -> Result<response::Response, error::WebRouterError> {
+        let omit_body = matches!(request.method, utils::HttpMethod::HEAD);
+        let mut response = match panic::catch_unwind(AssertUnwindSafe(|| self.route(request))) {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                let status_code = utils::HttpStatusCode::InternalServerError;
+                let mut response =
+                    response::Response::new(status_code.clone(), status_code.code().0.to_string());
+                response.set_keep_alive(false);
+                response
+            }
+        };
+        response.omit_body = omit_body;
+        return Ok(response);
+    }
+
     // This function works in two parts:
     // 1. It applies all the middlewares from the `middlewares` vector
     // 2. handle response generation from request by first getting all the user-registered routes
@@ -66,7 +155,7 @@ impl WebRouter {
     // hashmap to get the route which matches request's method and then finaly using that route's
     // handler function to generate the response for the request by providing a new `Context` with
     // the request as input to the handler function
-    pub fn handle_request(
+    fn route(
         &self,
         mut request: request::Request,
     ) -> Result<response::Response, error::WebRouterError> {
@@ -84,77 +173,167 @@ impl WebRouter {
             context = (middleware)(context);
         }
 
+        // an extension method that no route was registered for is not known to the server at all
+        if let utils::HttpMethod::Extension(method) = &context.request.method {
+            if !self
+                .routes
+                .values()
+                .any(|method_map| method_map.contains_key(method))
+            {
+                return Ok(response::Response::new(
+                    utils::HttpStatusCode::NotImplemented,
+                    utils::HttpStatusCode::NotImplemented.code().0.to_string(),
+                ));
+            }
+        }
+
+        // `OPTIONS *` asks about the server as a whole rather than a single path
+        if matches!(context.request.method, utils::HttpMethod::OPTIONS)
+            && context.request.path == "*"
+        {
+            return Ok(WebRouter::options_response(self.routes.values().collect()));
+        }
+
         // request path pattern matching with registered route paths
         match self.routes.get(&context.request.path) {
-            Some(path_map) => match path_map.get(&context.request.method.to_string()) {
+            Some(path_map) => match WebRouter::find_handler(path_map, &context.request.method) {
                 Some(route_handler) => {
                     // the request path, method `exactly` matches a registered route path, method
                     return Ok((route_handler)(context));
                 }
-                None => {
-                    // the request path `exactly` matches a registered route path but the method is
-                    // different
-                    return Ok(response::Response::new(
-                        utils::HttpStatusCode::MethodNotAllowed,
-                        format!("{}", utils::HttpStatusCode::MethodNotAllowed.code().0).to_string(),
-                    ));
-                }
+                // the request path `exactly` matches a registered route path but the method is
+                // different, a dynamic route path pattern matching the path may still have it
+                None => {}
             },
             // the request path does not `exactly` match a registered route path
-            None => {
-                for (route_path, method_map) in &self.routes {
-                    match WebRouter::match_dynamic_route(
-                        context.request.path.to_string(),
-                        route_path.to_string(),
-                    ) {
-                        Some(params) => match method_map.get(&context.request.method.to_string()) {
-                            Some(route_handler) => {
-                                // process and validate query parameters from request path
-                                let mut query_params = HashMap::new();
-                                match context.request.path.split('?').nth(1) {
-                                    Some(query) => {
-                                        for part in query.split('&') {
-                                            let mut key_value = part.split('=');
-                                            let key = key_value.next().unwrap_or("");
-                                            let value = key_value.next().unwrap_or("");
-                                            if key.is_empty() {
-                                                // If the key is empty, return a bad request response
-                                                return Ok(response::Response::new(
-                                                    utils::HttpStatusCode::BadRequest,
-                                                    format!(
-                                                        "{}",
-                                                        utils::HttpStatusCode::BadRequest.code().0
-                                                    )
-                                                    .to_string(),
-                                                ));
-                                            }
-                                            query_params.insert(key.to_string(), value.to_string());
+            None => {}
+        }
+
+        // method maps of the route paths and patterns that match the request path
+        let mut matched_maps = Vec::new();
+        for (route_path, method_map) in &self.routes {
+            match WebRouter::match_dynamic_route(
+                context.request.path.to_string(),
+                route_path.to_string(),
+            ) {
+                Some(params) => {
+                    match WebRouter::find_handler(method_map, &context.request.method) {
+                        Some(route_handler) => {
+                            // process and validate query parameters from request path
+                            let mut query_params = HashMap::new();
+                            match context.request.path.split('?').nth(1) {
+                                Some(query) => {
+                                    for part in query.split('&') {
+                                        let mut key_value = part.split('=');
+                                        let key = key_value.next().unwrap_or("");
+                                        let value = key_value.next().unwrap_or("");
+                                        if key.is_empty() {
+                                            // If the key is empty, return a bad request response
+                                            return Ok(response::Response::new(
+                                                utils::HttpStatusCode::BadRequest,
+                                                format!(
+                                                    "{}",
+                                                    utils::HttpStatusCode::BadRequest.code().0
+                                                )
+                                                .to_string(),
+                                            ));
                                         }
+                                        query_params.insert(key.to_string(), value.to_string());
                                     }
-                                    None => {}
                                 }
+                                None => {}
+                            }
 
-                                context.params = params;
-                                context.query_params = query_params;
+                            context.params = params;
+                            context.query_params = query_params;
 
-                                // the request path matches a registered dynamic route path pattern
-                                // with provided parameters
-                                return Ok((route_handler)(context));
-                            }
-                            None => {}
-                        },
-                        None => {}
+                            // the request path matches a registered dynamic route path pattern
+                            // with provided parameters
+                            return Ok((route_handler)(context));
+                        }
+                        None => matched_maps.push(method_map),
                     }
                 }
-                // the request path neither `exactly` matches any registered route,
-                // nor matches with any registered dynamic route path pattern
-                return Ok(response::Response::new(
-                    utils::HttpStatusCode::NotFound,
-                    format!("{}", utils::HttpStatusCode::NotFound.code().0).to_string(),
-                ));
+                None => {}
+            }
+        }
+        if !matched_maps.is_empty() {
+            // the request path matches registered route paths or patterns but none of them has
+            // a handler for the method
+            match context.request.method {
+                utils::HttpMethod::OPTIONS => {
+                    return Ok(WebRouter::options_response(matched_maps));
+                }
+                _ => return Ok(WebRouter::method_not_allowed_response(matched_maps)),
+            }
+        }
+        // the request path neither `exactly` matches any registered route,
+        // nor matches with any registered dynamic route path pattern
+        return Ok(response::Response::new(
+            utils::HttpStatusCode::NotFound,
+            format!("{}", utils::HttpStatusCode::NotFound.code().0).to_string(),
+        ));
+    }
+
+    // This function gets the handler registered for a method from a route's method map, a `HEAD`
+    // request is handled by the `GET` handler when no `HEAD` handler was registered
+    fn find_handler<'a>(
+        method_map: &'a HashMap<String, RouteHandler>,
+        method: &utils::HttpMethod,
+    ) -> Option<&'a RouteHandler> {
+        match method_map.get(&method.to_string()) {
+            Some(route_handler) => return Some(route_handler),
+            None => match method {
+                utils::HttpMethod::HEAD => {
+                    return method_map.get(&utils::HttpMethod::GET.to_string());
+                }
+                _ => return None,
+            },
+        }
+    }
+
+    // This function builds the answer to an `OPTIONS` request that has no handler of its own
+    fn options_response(method_maps: Vec<&HashMap<String, RouteHandler>>) -> response::Response {
+        let mut response = response::Response::new(utils::HttpStatusCode::NoContent, "");
+        response
+            .headers
+            .insert("Allow".to_string(), WebRouter::allowed_methods(method_maps));
+        return response;
+    }
+
+    // This function builds the `405` response for a path that has routes but none for the request's
+    // method, it has to tell the methods that are allowed in its `Allow` header
+    fn method_not_allowed_response(
+        method_maps: Vec<&HashMap<String, RouteHandler>>,
+    ) -> response::Response {
+        let mut response = response::Response::new(
+            utils::HttpStatusCode::MethodNotAllowed,
+            utils::HttpStatusCode::MethodNotAllowed.code().0.to_string(),
+        );
+        response
+            .headers
+            .insert("Allow".to_string(), WebRouter::allowed_methods(method_maps));
+        return response;
+    }
+
+    // This function builds the value of an `Allow` header, it lists every method registered in the
+    // given method maps along with the ones that are answered automatically (`HEAD` for a `GET`
+    // route, and `OPTIONS` itself)
+    fn allowed_methods(method_maps: Vec<&HashMap<String, RouteHandler>>) -> String {
+        let mut methods: Vec<String> = vec![utils::HttpMethod::OPTIONS.to_string()];
+        for method_map in method_maps {
+            for method in method_map.keys() {
+                methods.push(method.to_string());
+                if method == &utils::HttpMethod::GET.to_string() {
+                    methods.push(utils::HttpMethod::HEAD.to_string());
+                }
             }
         }
+        methods.sort();
+        methods.dedup();
+        return methods.join(", ");
     }
+
     // This function first removes the query parameters from the request path string, then
     // splits both the request path and route path into vectors by splitting at `/` (slashes).
     // It ensures the lengths of these vectors are the same. If they are, it zips the vectors
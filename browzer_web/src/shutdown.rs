@@ -0,0 +1,96 @@
+use crate::{connection, stats};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+// The shutdown state shared by a `WebServer`, its `ServerHandle`s and the connections it serves
+#[derive(Debug, Default)]
+pub(crate) struct ShutdownState {
+    requested: AtomicBool,
+    // the addresses the server is accepting connections on, a connection is made to each of them
+    // on shutdown to wake up the blocking accept loop
+    wake_addresses: Mutex<Vec<connection::SocketAddr>>,
+}
+
+impl ShutdownState {
+    pub(crate) fn is_requested(&self) -> bool {
+        return self.requested.load(Ordering::SeqCst);
+    }
+
+    // This function registers an address the accept loop is blocked on, it has to be called
+    // before checking `is_requested` so that a shutdown racing with it is not missed
+    pub(crate) fn register(&self, address: connection::SocketAddr) {
+        match self.wake_addresses.lock() {
+            Ok(mut addresses) => addresses.push(address),
+            Err(poisoned) => poisoned.into_inner().push(address),
+        }
+    }
+
+    fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        let addresses = match self.wake_addresses.lock() {
+            Ok(addresses) => addresses.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        for address in addresses {
+            match address {
+                connection::SocketAddr::Tcp(mut address) => {
+                    // a listener bound to the unspecified address is reached through loopback
+                    if address.ip().is_unspecified() {
+                        address.set_ip(match address.ip() {
+                            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                        });
+                    }
+                    let _ = TcpStream::connect_timeout(&address, Duration::from_secs(1));
+                }
+                #[cfg(unix)]
+                connection::SocketAddr::Unix(Some(path)) => {
+                    let _ = std::os::unix::net::UnixStream::connect(path);
+                }
+                #[cfg(unix)]
+                connection::SocketAddr::Unix(None) => {}
+            }
+        }
+    }
+}
+
+// A handle to a running (or about to run) `WebServer` which can be sent to other threads to stop
+// it, get one with `WebServer::handle` before calling `WebServer::listen`
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    state: Arc<ShutdownState>,
+    counters: Arc<stats::ServerCounters>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        state: Arc<ShutdownState>,
+        counters: Arc<stats::ServerCounters>,
+    ) -> ServerHandle {
+        return ServerHandle { state, counters };
+    }
+
+    // This function asks the server to shut down gracefully: it stops accepting new connections,
+    // closes persistent connections once their current request is answered and lets the requests
+    // in flight finish within `ServerConfig::shutdown_timeout`, after which `WebServer::listen`
+    // returns. It does not wait for any of that to happen.
+    pub fn shutdown(&self) {
+        self.state.request();
+    }
+
+    // This function tells whether a shutdown was requested for the server
+    pub fn is_shutdown(&self) -> bool {
+        return self.state.is_requested();
+    }
+
+    // This function returns a snapshot of the connection and request counters of the server
+    pub fn stats(&self) -> stats::ServerStats {
+        return self.counters.snapshot();
+    }
+}
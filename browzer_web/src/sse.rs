@@ -0,0 +1,246 @@
+use crate::{error, response, shutdown, utils};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// how many bytes of events that are already waiting are sent to the client in a single chunk
+const MAX_BATCH_SIZE: usize = 64 * 1024;
+// how often a stream waiting for events checks whether the server is shutting down
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// the shortest time between two heartbeats, a shorter `heartbeat_interval` is raised to it
+const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+// A single Server-Sent Event, see the `text/event-stream` format in the HTML standard
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Event {
+    // the type of the event, clients listen for it with `addEventListener`, `message` if unset
+    pub event: Option<String>,
+    pub data: String,
+    // the id the client sends back in `Last-Event-ID` when it reconnects
+    pub id: Option<String>,
+    // how long the client waits before it reconnects after losing the connection
+    pub retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Event {
+        return Event {
+            data: data.to_string(),
+            ..Event::default()
+        };
+    }
+
+    pub fn event(mut self, event: &str) -> Event {
+        self.event = Some(event.to_string());
+        return self;
+    }
+
+    pub fn id(mut self, id: &str) -> Event {
+        self.id = Some(id.to_string());
+        return self;
+    }
+
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        return self;
+    }
+
+    // This function encodes the event as a block of `field: value` lines ended by a blank line,
+    // every line of `data` becomes a `data` field of its own. Line breaks can not be part of the
+    // other fields and are dropped from them
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+        let mut output = String::new();
+        if let Some(event) = &self.event {
+            output.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            output.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = &self.retry {
+            output.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            output.push_str(&format!("data: {}\n", line));
+        }
+        output.push('\n');
+        return output.into_bytes();
+    }
+}
+
+// The handle events are emitted through onto an event stream, it can be cloned and sent to other
+// threads. The stream ends once every `EventSender` of it is dropped
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    sender: mpsc::Sender<Vec<u8>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl EventSender {
+    // This function queues an event to be sent to the client, it fails once the client has
+    // disconnected
+    pub fn send(&self, event: Event) -> Result<(), error::EventStreamError> {
+        return self.send_bytes(event.to_bytes());
+    }
+
+    // This function queues a comment, which clients ignore
+    pub fn comment(&self, comment: &str) -> Result<(), error::EventStreamError> {
+        let mut output = String::new();
+        for line in comment.replace("\r\n", "\n").split(['\r', '\n']) {
+            output.push_str(&format!(": {}\n", line));
+        }
+        output.push('\n');
+        return self.send_bytes(output.into_bytes());
+    }
+
+    // This function tells whether the client has disconnected (or the server stopped sending the
+    // stream), nothing sent from then on reaches it
+    pub fn is_closed(&self) -> bool {
+        return self.closed.load(Ordering::SeqCst);
+    }
+
+    fn send_bytes(&self, bytes: Vec<u8>) -> Result<(), error::EventStreamError> {
+        if self.is_closed() {
+            return Err(error::EventStreamError::Disconnected);
+        }
+        match self.sender.send(bytes) {
+            Ok(_) => return Ok(()),
+            Err(_) => return Err(error::EventStreamError::Disconnected),
+        }
+    }
+}
+
+// The chunks of an event stream's body, what the `EventSender`s queued is sent as it comes in and
+// a heartbeat comment is sent whenever nothing was queued for `heartbeat_interval`. Writing the
+// heartbeat fails once the client is gone, which ends the stream even when no events are sent. The
+// stream also ends when the server starts shutting down, so that it does not hold up the shutdown
+struct Events {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    heartbeat_interval: Duration,
+    closed: Arc<AtomicBool>,
+    shutdown: Option<Arc<shutdown::ShutdownState>>,
+}
+
+impl Events {
+    // This function creates the body of an event stream along with the sender feeding it, a
+    // `heartbeat_interval` shorter than `MIN_HEARTBEAT_INTERVAL` is raised to it
+    fn new(
+        heartbeat_interval: Duration,
+        shutdown: Option<Arc<shutdown::ShutdownState>>,
+    ) -> (Events, EventSender) {
+        let (sender, receiver) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let events = Events {
+            receiver,
+            heartbeat_interval: heartbeat_interval.max(MIN_HEARTBEAT_INTERVAL),
+            closed: Arc::clone(&closed),
+            shutdown,
+        };
+        return (events, EventSender { sender, closed });
+    }
+}
+
+impl Iterator for Events {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let heartbeat_at = Instant::now() + self.heartbeat_interval;
+        let mut chunk = loop {
+            if let Some(shutdown) = &self.shutdown {
+                if shutdown.is_requested() {
+                    return None;
+                }
+            }
+            // the receiver is polled before a heartbeat which is due, so that events which are
+            // already waiting go out first
+            let timeout = heartbeat_at
+                .saturating_duration_since(Instant::now())
+                .min(SHUTDOWN_CHECK_INTERVAL);
+            match self.receiver.recv_timeout(timeout) {
+                Ok(bytes) => break bytes,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if Instant::now() >= heartbeat_at {
+                        return Some(Ok(b": heartbeat\n\n".to_vec()));
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+            };
+        };
+        // events which are already waiting go out together
+        while chunk.len() < MAX_BATCH_SIZE {
+            match self.receiver.try_recv() {
+                Ok(bytes) => chunk.extend_from_slice(&bytes),
+                Err(_) => break,
+            };
+        }
+        return Some(Ok(chunk));
+    }
+}
+
+// the body is dropped once it is written out or writing it failed
+impl Drop for Events {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+// This function creates a `text/event-stream` response along with the sender its events are
+// emitted through, see `Context::send_events`. The stream ends once `shutdown` is requested
+pub(crate) fn event_stream(
+    heartbeat_interval: Duration,
+    shutdown: Option<Arc<shutdown::ShutdownState>>,
+) -> (response::Response, EventSender) {
+    let (events, sender) = Events::new(heartbeat_interval, shutdown);
+
+    let mut response = response::Response::stream(
+        utils::HttpStatusCode::OK,
+        response::StreamBody::from_fallible_chunks(events),
+    );
+    response
+        .headers
+        .insert("Content-Type".to_string(), "text/event-stream".to_string());
+    response
+        .headers
+        .insert("Cache-Control".to_string(), "no-cache".to_string());
+    return (response, sender);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_waiting_events_before_a_heartbeat() {
+        let (mut events, sender) = Events::new(Duration::ZERO, None);
+        sender.send(Event::new("first")).unwrap();
+        sender.send(Event::new("second")).unwrap();
+        let chunk = events.next().unwrap().unwrap();
+        assert_eq!(chunk, b"data: first\n\ndata: second\n\n".to_vec());
+    }
+
+    #[test]
+    fn raises_a_heartbeat_interval_of_zero() {
+        let (mut events, sender) = Events::new(Duration::ZERO, None);
+        let started = Instant::now();
+        assert_eq!(events.next().unwrap().unwrap(), b": heartbeat\n\n".to_vec());
+        assert!(started.elapsed() >= MIN_HEARTBEAT_INTERVAL);
+        drop(sender);
+        assert!(events.next().is_none());
+    }
+
+    #[test]
+    fn ends_once_the_server_shuts_down() {
+        let shutdown = Arc::new(shutdown::ShutdownState::default());
+        let (mut events, sender) =
+            Events::new(Duration::from_secs(60), Some(Arc::clone(&shutdown)));
+        shutdown::ServerHandle::new(shutdown, Arc::default()).shutdown();
+        assert!(events.next().is_none());
+        drop(events);
+        assert!(sender.is_closed());
+    }
+}
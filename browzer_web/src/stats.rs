@@ -0,0 +1,89 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+// The counters a `WebServer` keeps while it is running, shared with its `ServerHandle`s
+#[derive(Debug, Default)]
+pub(crate) struct ServerCounters {
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    requests: AtomicU64,
+    timed_out: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl ServerCounters {
+    // a connection was accepted and handed over to the worker threads
+    pub(crate) fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // a connection was closed, either after being served or because it could not be served
+    pub(crate) fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn request_handled(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // a connection was closed because the client was too slow to send a request or take in a
+    // response
+    pub(crate) fn connection_timed_out(&self) {
+        self.timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // a connection was turned away with `503 Service Unavailable` because the server was overloaded
+    pub(crate) fn connection_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ServerStats {
+        return ServerStats {
+            connections: self.connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::SeqCst),
+            requests: self.requests.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        };
+    }
+}
+
+// A connection counted as active by `ServerCounters::connection_opened`, it is counted as closed
+// when this is dropped, which also happens when the job serving the connection panics or never
+// gets to run
+pub(crate) struct OpenConnection {
+    counters: Arc<ServerCounters>,
+}
+
+impl OpenConnection {
+    pub(crate) fn new(counters: Arc<ServerCounters>) -> OpenConnection {
+        counters.connection_opened();
+        return OpenConnection { counters };
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.counters.connection_closed();
+    }
+}
+
+// A snapshot of the counters of a `WebServer`, get one with `ServerHandle::stats`
+#[derive(Debug, Clone, Default)]
+pub struct ServerStats {
+    // number of connections accepted since the server started listening
+    pub connections: u64,
+    // number of connections which are queued or being served right now, after a graceful
+    // shutdown this is the number of connections that did not finish within the timeout
+    pub active_connections: u64,
+    // number of requests answered since the server started listening
+    pub requests: u64,
+    // number of connections closed because a read or write timeout ran out
+    pub timed_out: u64,
+    // number of connections turned away because every worker thread was busy and the queue was
+    // full, see `OverloadPolicy::Reject`
+    pub rejected: u64,
+}
@@ -0,0 +1,270 @@
+use crate::{connection, error};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// An encrypted connection, the TLS handshake is already completed when it is created
+#[derive(Debug)]
+pub struct TlsStream {
+    stream: rustls::StreamOwned<rustls::ServerConnection, TcpStream>,
+    // whether the `close_notify` was sent already
+    closed: bool,
+}
+
+impl TlsStream {
+    // This function returns the TCP socket the TLS session runs over
+    pub fn socket(&self) -> &TcpStream {
+        return &self.stream.sock;
+    }
+
+    // This function tells the client that the connection is being closed on purpose (TLS
+    // `close_notify`), so it can tell a complete response from a truncated one. Nothing can be
+    // written to the stream afterwards
+    pub(crate) fn close_notify(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        self.stream.conn.send_close_notify();
+        let _ = self
+            .stream
+            .sock
+            .set_write_timeout(Some(Duration::from_secs(1)));
+        let _ = self.stream.conn.complete_io(&mut self.stream.sock);
+    }
+
+    // This function collects the negotiated parameters of the TLS session
+    pub fn session_info(&self) -> connection::TlsInfo {
+        let conn = &self.stream.conn;
+        return connection::TlsInfo {
+            protocol_version: match conn.protocol_version() {
+                Some(version) => format!("{:?}", version),
+                None => String::new(),
+            },
+            cipher_suite: match conn.negotiated_cipher_suite() {
+                Some(suite) => format!("{:?}", suite.suite()),
+                None => String::new(),
+            },
+            alpn_protocol: conn
+                .alpn_protocol()
+                .map(|protocol| String::from_utf8_lossy(protocol).to_string()),
+            server_name: conn.server_name().map(|name| name.to_string()),
+        };
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        return self.stream.read(buf);
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.stream.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.stream.flush();
+    }
+}
+
+// the client is told that the connection is being closed on purpose, see `close_notify`
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        self.close_notify();
+    }
+}
+
+// The socket of a connection being set up, before its requests are read through a
+// `connection::TimedStream`. All reads from it have to be over by `deadline`, however the client
+// spreads out what it sends, so that it can not hold on to a worker thread by sending the start of
+// the connection (e.g. the TLS handshake) slowly
+pub(crate) struct DeadlineSocket<'a> {
+    sock: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> DeadlineSocket<'a> {
+    pub(crate) fn new(sock: &'a TcpStream, deadline: Instant) -> DeadlineSocket<'a> {
+        return DeadlineSocket { sock, deadline };
+    }
+}
+
+impl Read for DeadlineSocket<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "read deadline exceeded",
+            ));
+        }
+        self.sock.set_read_timeout(Some(remaining))?;
+        match self.sock.read(buf) {
+            // depending on the platform a socket read timeout is reported as either of these
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+            }
+            result => return result,
+        }
+    }
+}
+
+impl Write for DeadlineSocket<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.sock.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.sock.flush();
+    }
+}
+
+// The TLS configuration of a `WebServer`, every accepted connection goes through a TLS handshake
+// using the certificate chain and private key it was created with before any request is read
+#[derive(Debug, Clone)]
+pub struct TlsAcceptor {
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsAcceptor {
+    // This function creates an acceptor from PEM encoded files, `cert_chain_path` holds the
+    // server certificate followed by any intermediate certificates and `key_path` holds the
+    // private key (PKCS#1, PKCS#8 or SEC1)
+    pub fn from_pem_files<P: AsRef<Path>>(
+        cert_chain_path: P,
+        key_path: P,
+    ) -> Result<TlsAcceptor, error::WebServerError> {
+        let cert_chain = match fs::read(cert_chain_path.as_ref()) {
+            Ok(cert_chain) => cert_chain,
+            Err(e) => {
+                return Err(error::WebServerError::TlsError(format!(
+                    "failed to read certificate chain {}: {}",
+                    cert_chain_path.as_ref().display(),
+                    e
+                )));
+            }
+        };
+        let key = match fs::read(key_path.as_ref()) {
+            Ok(key) => key,
+            Err(e) => {
+                return Err(error::WebServerError::TlsError(format!(
+                    "failed to read private key {}: {}",
+                    key_path.as_ref().display(),
+                    e
+                )));
+            }
+        };
+        return TlsAcceptor::from_pem(&cert_chain, &key);
+    }
+
+    // This function creates an acceptor from an in-memory PEM encoded certificate chain and
+    // private key, see `from_pem_files`
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Result<TlsAcceptor, error::WebServerError> {
+        let cert_chain =
+            match CertificateDer::pem_slice_iter(cert_chain).collect::<Result<Vec<_>, _>>() {
+                Ok(cert_chain) if !cert_chain.is_empty() => cert_chain,
+                Ok(_) => {
+                    return Err(error::WebServerError::TlsError(
+                        "no certificate found in the certificate chain".to_string(),
+                    ));
+                }
+                Err(e) => {
+                    return Err(error::WebServerError::TlsError(format!(
+                        "invalid certificate chain: {}",
+                        e
+                    )));
+                }
+            };
+        let key = match PrivateKeyDer::from_pem_slice(key) {
+            Ok(key) => key,
+            Err(e) => {
+                return Err(error::WebServerError::TlsError(format!(
+                    "invalid private key: {}",
+                    e
+                )));
+            }
+        };
+
+        let mut config = match rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        {
+            Ok(builder) => match builder
+                .with_no_client_auth()
+                .with_single_cert(cert_chain, key)
+            {
+                Ok(config) => config,
+                Err(e) => return Err(error::WebServerError::TlsError(e.to_string())),
+            },
+            Err(e) => return Err(error::WebServerError::TlsError(e.to_string())),
+        };
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        return Ok(TlsAcceptor {
+            config: Arc::new(config),
+        });
+    }
+
+    // This function performs the TLS handshake on an accepted connection and returns the
+    // encrypted stream requests are read from. `received` is the start of the handshake if it was
+    // already read off the connection, e.g. along with a PROXY protocol header. The handshake has
+    // to be over by `deadline`
+    pub(crate) fn accept(
+        &self,
+        sock: TcpStream,
+        mut received: &[u8],
+        deadline: Instant,
+    ) -> Result<connection::Stream, error::WebServerError> {
+        let mut conn = match rustls::ServerConnection::new(Arc::clone(&self.config)) {
+            Ok(conn) => conn,
+            Err(e) => return Err(error::WebServerError::TlsError(e.to_string())),
+        };
+        while !received.is_empty() {
+            conn.read_tls(&mut received)?;
+            match conn.process_new_packets() {
+                Ok(_) => {}
+                Err(e) => {
+                    return Err(error::WebServerError::TlsError(format!(
+                        "handshake failed: {}",
+                        e
+                    )));
+                }
+            };
+        }
+        while conn.is_handshaking() {
+            match conn.complete_io(&mut DeadlineSocket::new(&sock, deadline)) {
+                Ok(_) => {}
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Err(error::WebServerError::IO(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "TLS handshake timed out",
+                    )));
+                }
+                Err(e) => {
+                    return Err(error::WebServerError::TlsError(format!(
+                        "handshake failed: {}",
+                        e
+                    )));
+                }
+            }
+        }
+        // the timeouts of the reads that follow are up to the `TimedStream` they go through
+        sock.set_read_timeout(None)?;
+        return Ok(connection::Stream::Tls(Box::new(TlsStream {
+            stream: rustls::StreamOwned::new(conn, sock),
+            closed: false,
+        })));
+    }
+}
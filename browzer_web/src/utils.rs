@@ -1,8 +1,39 @@
-use std::time;
 use crate::error;
+use std::{
+    io::{self, BufRead, Read},
+    time,
+};
 
+pub mod chunked;
 pub mod thread_pool;
 
+// This function reads a single line off a buffered reader and strips the line terminator from it,
+// HTTP lines end with CRLF but a bare LF is accepted too. `Ok(None)` is returned at EOF.
+//
+// At most `limit` bytes (line terminator included) are read, a longer line fails with `too_long`
+// without reading the rest of it
+pub fn read_line<R: BufRead>(
+    reader: &mut R,
+    limit: usize,
+    too_long: error::RequestError,
+) -> Result<Option<String>, error::WebServerError> {
+    let mut line = Vec::new();
+    match reader
+        .by_ref()
+        .take(limit as u64 + 1)
+        .read_until(b'\n', &mut line)
+    {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(e) => return Err(error::WebServerError::IO(e)),
+    };
+    if line.len() > limit || (line.len() == limit && line.last() != Some(&b'\n')) {
+        return Err(error::WebServerError::RequestParseError(too_long));
+    }
+    let line = String::from_utf8_lossy(&line);
+    return Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()));
+}
+
 // If there is a route defined as `/menu/items/`, a person would probably not want to add the
 // slash at the end everytime they are visiting this path, so this function removes the slashes at
 // the end from such paths making it easier and simpler for both the end user and developer
@@ -26,20 +57,66 @@ pub fn format_path_by_slashes(mut path: String) -> Result<String, error::WebRout
     return Ok(path);
 }
 
-#[derive(Debug)]
+// This function tells whether an error comes from a read or write running out of time
+pub fn is_timeout(err: &error::WebServerError) -> bool {
+    match err {
+        error::WebServerError::IO(e) => {
+            e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum HttpMethod {
     GET,
     POST,
+    PUT,
     PATCH,
     DELETE,
+    HEAD,
+    OPTIONS,
+    TRACE,
+    CONNECT,
+    // any other method, HTTP allows methods to be added beyond the standard ones
+    Extension(String),
 }
 impl HttpMethod {
+    // This function parses the method of a request line, method names are case-sensitive so
+    // anything other than a standard method is an `Extension` method. `None` is returned if the
+    // method is not a valid token
+    pub fn parse(method: &str) -> Option<HttpMethod> {
+        let is_token_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if method.is_empty() || !method.chars().all(is_token_char) {
+            return None;
+        }
+        let method = match method {
+            "GET" => HttpMethod::GET,
+            "POST" => HttpMethod::POST,
+            "PUT" => HttpMethod::PUT,
+            "PATCH" => HttpMethod::PATCH,
+            "DELETE" => HttpMethod::DELETE,
+            "HEAD" => HttpMethod::HEAD,
+            "OPTIONS" => HttpMethod::OPTIONS,
+            "TRACE" => HttpMethod::TRACE,
+            "CONNECT" => HttpMethod::CONNECT,
+            _ => HttpMethod::Extension(method.to_string()),
+        };
+        return Some(method);
+    }
+
     pub fn to_string(&self) -> String {
         match self {
             HttpMethod::GET => "GET",
             HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
             HttpMethod::PATCH => "PATCH",
             HttpMethod::DELETE => "DELETE",
+            HttpMethod::HEAD => "HEAD",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::TRACE => "TRACE",
+            HttpMethod::CONNECT => "CONNECT",
+            HttpMethod::Extension(method) => method,
         }
         .to_string()
     }
@@ -47,6 +124,8 @@ impl HttpMethod {
 
 #[derive(Debug, Clone)]
 pub enum HttpStatusCode {
+    Continue,
+    SwitchingProtocols,
     OK,
     Created,
     Accepted,
@@ -60,6 +139,12 @@ pub enum HttpStatusCode {
     Forbidden,
     NotFound,
     MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    URITooLong,
+    ExpectationFailed,
+    UpgradeRequired,
+    RequestHeaderFieldsTooLarge,
     InternalServerError,
     NotImplemented,
     BadGateway,
@@ -68,6 +153,8 @@ pub enum HttpStatusCode {
 impl HttpStatusCode {
     pub fn code(&self) -> (&str, u16) {
         match self {
+            HttpStatusCode::Continue => ("Continue", 100),
+            HttpStatusCode::SwitchingProtocols => ("Switching Protocols", 101),
             HttpStatusCode::OK => ("OK", 200),
             HttpStatusCode::Created => ("Created", 201),
             HttpStatusCode::Accepted => ("Accepted", 202),
@@ -81,6 +168,12 @@ impl HttpStatusCode {
             HttpStatusCode::Forbidden => ("Forbidden", 403),
             HttpStatusCode::NotFound => ("Not Found", 404),
             HttpStatusCode::MethodNotAllowed => ("Method Not Allowed", 405),
+            HttpStatusCode::RequestTimeout => ("Request Timeout", 408),
+            HttpStatusCode::PayloadTooLarge => ("Payload Too Large", 413),
+            HttpStatusCode::URITooLong => ("URI Too Long", 414),
+            HttpStatusCode::ExpectationFailed => ("Expectation Failed", 417),
+            HttpStatusCode::UpgradeRequired => ("Upgrade Required", 426),
+            HttpStatusCode::RequestHeaderFieldsTooLarge => ("Request Header Fields Too Large", 431),
             HttpStatusCode::InternalServerError => ("Internal Server Error", 500),
             HttpStatusCode::NotImplemented => ("Not Implemented", 501),
             HttpStatusCode::BadGateway => ("Bad Gateway", 502),
@@ -127,3 +220,62 @@ impl Default for Cookie {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This function reads a line of at most `limit` bytes off `input`
+    fn read(input: &[u8], limit: usize) -> Result<Option<String>, error::WebServerError> {
+        return read_line(
+            &mut &input[..],
+            limit,
+            error::RequestError::RequestLineTooLongError(limit),
+        );
+    }
+
+    #[test]
+    fn reads_a_line_up_to_the_limit() {
+        // the limit takes in the line break
+        assert_eq!(
+            read(b"GET / HTTP/1.1\r\n", 16).unwrap().unwrap(),
+            "GET / HTTP/1.1"
+        );
+        assert_eq!(
+            read(b"GET / HTTP/1.1\n", 15).unwrap().unwrap(),
+            "GET / HTTP/1.1"
+        );
+        assert!(read(b"", 16).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_a_line_one_byte_over_the_limit() {
+        for (input, limit) in [
+            (&b"GET / HTTP/1.1\r\n"[..], 15),
+            (&b"GET / HTTP/1.1\n"[..], 14),
+            // without its line break the line can not be told apart from a longer one
+            (&b"GET / HTTP/1.1"[..], 14),
+        ] {
+            match read(input, limit) {
+                Err(error::WebServerError::RequestParseError(
+                    error::RequestError::RequestLineTooLongError(_),
+                )) => {}
+                other => panic!("{:?} was not rejected: {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_the_rest_of_a_line_over_the_limit_unread() {
+        let input = b"GET /aaaaaaaaaaaaaaaaaaaaaaaa HTTP/1.1\r\n";
+        let mut reader = &input[..];
+        assert!(read_line(
+            &mut reader,
+            8,
+            error::RequestError::RequestLineTooLongError(8)
+        )
+        .is_err());
+        // no more than one byte past the limit is read
+        assert_eq!(reader.len(), input.len() - 9);
+    }
+}
@@ -0,0 +1,462 @@
+use crate::{config, error::*};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+// longest chunk size line accepted, chunk extensions included
+pub(crate) const MAX_CHUNK_SIZE_LINE_LENGTH: usize = 1024;
+
+// This function decodes a body sent with `Transfer-Encoding: chunked` off a buffered reader. Each
+// chunk starts with its size in hex on its own line (optionally followed by `;`-separated chunk
+// extensions, which are ignored), followed by the chunk data and a line break. A chunk of size 0
+// ends the body and is followed by optional trailer fields and an empty line.
+//
+// It returns the decoded body along with the trailer fields, the reader is left right after the
+// end of the chunked body. The decoded body is bounded by `max_body_size` and the trailer fields
+// by the limits on header fields.
+pub fn decode<R: BufRead>(
+    reader: &mut R,
+    limits: &config::RequestLimits,
+) -> Result<(Vec<u8>, HashMap<String, String>), WebServerError> {
+    let mut body = Vec::new();
+
+    loop {
+        let size_line = read_line(
+            reader,
+            MAX_CHUNK_SIZE_LINE_LENGTH,
+            RequestError::InvalidChunkedBodyError("chunk size line is too long".to_string()),
+        )?;
+        let size = chunk_size(&size_line, body.len(), limits)?;
+        if size == 0 {
+            break;
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+
+        // chunk data is followed by a line break and nothing else
+        if !read_line(
+            reader,
+            2,
+            RequestError::InvalidChunkedBodyError(
+                "chunk data is longer than its declared size".to_string(),
+            ),
+        )?
+        .is_empty()
+        {
+            return Err(WebServerError::RequestParseError(
+                RequestError::InvalidChunkedBodyError(
+                    "chunk data is longer than its declared size".to_string(),
+                ),
+            ));
+        }
+    }
+
+    // parse trailer fields until the empty line which ends the chunked body
+    let mut trailers = HashMap::new();
+    let mut trailers_size = 0;
+    loop {
+        let line = read_line(
+            reader,
+            limits.max_header_size.saturating_sub(trailers_size),
+            RequestError::HeaderFieldsTooLargeError(format!(
+                "trailer fields are larger than {} bytes",
+                limits.max_header_size
+            )),
+        )?;
+        if line.is_empty() {
+            break;
+        }
+        trailers_size += line.len() + 2;
+        add_trailer(&mut trailers, &line, limits)?;
+    }
+
+    return Ok((body, trailers));
+}
+
+// The decoding of a chunked body which arrives in pieces, as it does on the I/O threads. Unlike
+// `decode` it never waits for more input: `feed` decodes as much of what has arrived as it can
+// and keeps its place until the rest comes in, so that no part of the body is decoded twice
+#[derive(Debug, Default)]
+pub(crate) struct ChunkedDecoder {
+    state: DecoderState,
+    body: Vec<u8>,
+    trailers: HashMap<String, String>,
+    trailers_size: usize,
+}
+
+#[derive(Debug, Default)]
+enum DecoderState {
+    // reading the size line of the next chunk
+    #[default]
+    Size,
+    // reading the data of a chunk, with this many bytes of it left
+    Data(usize),
+    // reading the line break which follows the data of a chunk
+    DataEnd,
+    // reading the trailer fields after the last chunk
+    Trailers,
+    // the body is complete
+    Done,
+}
+
+impl ChunkedDecoder {
+    // This function decodes the body from `input`, which carries on from the input fed before,
+    // and returns the number of bytes of it used up. A line is only used once its line break has
+    // arrived, whatever is left over has to be fed again along with the input that follows it
+    pub(crate) fn feed(
+        &mut self,
+        input: &[u8],
+        limits: &config::RequestLimits,
+    ) -> Result<usize, WebServerError> {
+        let mut consumed = 0;
+        loop {
+            let input = &input[consumed..];
+            let line = match self.state {
+                DecoderState::Done => break,
+                DecoderState::Data(left) => {
+                    if input.is_empty() {
+                        break;
+                    }
+                    let length = left.min(input.len());
+                    self.body.extend_from_slice(&input[..length]);
+                    consumed += length;
+                    self.state = match left - length {
+                        0 => DecoderState::DataEnd,
+                        left => DecoderState::Data(left),
+                    };
+                    continue;
+                }
+                DecoderState::Size => complete_line(
+                    input,
+                    MAX_CHUNK_SIZE_LINE_LENGTH,
+                    RequestError::InvalidChunkedBodyError(
+                        "chunk size line is too long".to_string(),
+                    ),
+                )?,
+                DecoderState::DataEnd => complete_line(
+                    input,
+                    2,
+                    RequestError::InvalidChunkedBodyError(
+                        "chunk data is longer than its declared size".to_string(),
+                    ),
+                )?,
+                DecoderState::Trailers => complete_line(
+                    input,
+                    limits.max_header_size.saturating_sub(self.trailers_size),
+                    RequestError::HeaderFieldsTooLargeError(format!(
+                        "trailer fields are larger than {} bytes",
+                        limits.max_header_size
+                    )),
+                )?,
+            };
+            let line = match line {
+                Some((line, length)) => {
+                    consumed += length;
+                    line
+                }
+                None => break,
+            };
+
+            self.state = match self.state {
+                DecoderState::Size => match chunk_size(&line, self.body.len(), limits)? {
+                    0 => DecoderState::Trailers,
+                    size => DecoderState::Data(size),
+                },
+                DecoderState::DataEnd if line.is_empty() => DecoderState::Size,
+                DecoderState::DataEnd => {
+                    return Err(WebServerError::RequestParseError(
+                        RequestError::InvalidChunkedBodyError(
+                            "chunk data is longer than its declared size".to_string(),
+                        ),
+                    ));
+                }
+                _ if line.is_empty() => DecoderState::Done,
+                _ => {
+                    self.trailers_size += line.len() + 2;
+                    add_trailer(&mut self.trailers, &line, limits)?;
+                    DecoderState::Trailers
+                }
+            };
+        }
+        return Ok(consumed);
+    }
+
+    // whether the whole body, trailer fields included, has been decoded
+    pub(crate) fn is_done(&self) -> bool {
+        return matches!(self.state, DecoderState::Done);
+    }
+
+    // This function returns the decoded body along with the trailer fields
+    pub(crate) fn finish(self) -> (Vec<u8>, HashMap<String, String>) {
+        return (self.body, self.trailers);
+    }
+}
+
+// This function writes `data` as a single chunk of a chunked body, empty data is skipped as an
+// empty chunk would mark the end of the body
+pub fn write_chunk<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    writer.write_all(format!("{:X}\r\n", data.len()).as_bytes())?;
+    writer.write_all(data)?;
+    writer.write_all(b"\r\n")
+}
+
+// This function writes the last (empty) chunk which ends a chunked body, no trailers are sent
+pub fn write_last_chunk<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(b"0\r\n\r\n")?;
+    writer.flush()
+}
+
+// parses the size of a chunk out of its size line, chunk extensions are ignored. The size is hex
+// digits only, `from_str_radix` would take a leading `+` too
+pub(crate) fn parse_size_line(size_line: &str) -> Option<usize> {
+    let size = size_line.split(';').next().unwrap_or("").trim();
+    if !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    return usize::from_str_radix(size, 16).ok();
+}
+
+// parses the size line of the next chunk, the body may not grow past `max_body_size` with it
+fn chunk_size(
+    size_line: &str,
+    body_size: usize,
+    limits: &config::RequestLimits,
+) -> Result<usize, WebServerError> {
+    let size = match parse_size_line(size_line) {
+        Some(size) => size,
+        None => {
+            return Err(WebServerError::RequestParseError(
+                RequestError::InvalidChunkedBodyError(format!(
+                    "invalid chunk size line `{}`",
+                    size_line
+                )),
+            ));
+        }
+    };
+    if size > limits.max_body_size - body_size {
+        return Err(WebServerError::RequestParseError(
+            RequestError::PayloadTooLargeError(limits.max_body_size),
+        ));
+    }
+    return Ok(size);
+}
+
+// adds a trailer field line to the trailer fields read so far
+fn add_trailer(
+    trailers: &mut HashMap<String, String>,
+    line: &str,
+    limits: &config::RequestLimits,
+) -> Result<(), WebServerError> {
+    if trailers.len() >= limits.max_header_count {
+        return Err(WebServerError::RequestParseError(
+            RequestError::HeaderFieldsTooLargeError(format!(
+                "more than {} trailer fields",
+                limits.max_header_count
+            )),
+        ));
+    }
+    match line.split_once(':') {
+        Some((name, value)) => {
+            trailers.insert(name.trim().to_string(), value.trim().to_string());
+            return Ok(());
+        }
+        None => {
+            return Err(WebServerError::RequestParseError(
+                RequestError::InvalidChunkedBodyError(format!("invalid trailer field `{}`", line)),
+            ));
+        }
+    }
+}
+
+// takes the line at the start of `input` along with its length, if its line break has arrived.
+// Like `utils::read_line` the line may take up at most `limit` bytes, line break included
+fn complete_line(
+    input: &[u8],
+    limit: usize,
+    too_long: RequestError,
+) -> Result<Option<(String, usize)>, WebServerError> {
+    match input[..input.len().min(limit)]
+        .iter()
+        .position(|byte| *byte == b'\n')
+    {
+        Some(end) => {
+            let line = String::from_utf8_lossy(&input[..end]);
+            return Ok(Some((line.trim_end_matches('\r').to_string(), end + 1)));
+        }
+        None if input.len() >= limit => return Err(WebServerError::RequestParseError(too_long)),
+        None => return Ok(None),
+    }
+}
+
+// reads a line that is required to exist, running into EOF in the middle of a chunked body is an
+// error
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    limit: usize,
+    too_long: RequestError,
+) -> Result<String, WebServerError> {
+    match super::read_line(reader, limit, too_long) {
+        Ok(Some(line)) => Ok(line),
+        Ok(None) => Err(WebServerError::IO(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "connection closed in the middle of a chunked body",
+        ))),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a body with a chunk extension and a trailer field, followed by the start of the next request
+    const BODY: &[u8] =
+        b"5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nX-Checksum: abc\r\n\r\nGET / HTTP/1.1\r\n";
+
+    // This function decodes `input` with `decode`, the reader is expected to be left right after
+    // the end of the body
+    fn decode_all(
+        input: &[u8],
+        limits: &config::RequestLimits,
+    ) -> Result<(Vec<u8>, HashMap<String, String>), WebServerError> {
+        let mut reader = input;
+        let decoded = decode(&mut reader, limits)?;
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+        return Ok(decoded);
+    }
+
+    // This function feeds `input` to a `ChunkedDecoder` in `pieces`, the way it arrives on an I/O
+    // thread, carrying over what a `feed` did not use to the next one
+    fn feed_all(
+        pieces: &[&[u8]],
+        limits: &config::RequestLimits,
+    ) -> Result<(Vec<u8>, HashMap<String, String>), WebServerError> {
+        let mut decoder = ChunkedDecoder::default();
+        let mut pending = Vec::new();
+        for piece in pieces {
+            pending.extend_from_slice(piece);
+            let consumed = decoder.feed(&pending, limits)?;
+            pending.drain(..consumed);
+        }
+        assert!(decoder.is_done());
+        assert_eq!(pending, b"GET / HTTP/1.1\r\n");
+        return Ok(decoder.finish());
+    }
+
+    fn assert_decoded(decoded: (Vec<u8>, HashMap<String, String>)) {
+        let (body, trailers) = decoded;
+        assert_eq!(body, b"hello, world");
+        assert_eq!(trailers.len(), 1);
+        assert_eq!(trailers["X-Checksum"], "abc");
+    }
+
+    #[test]
+    fn decodes_chunk_extensions_and_trailers() {
+        let limits = config::RequestLimits::default();
+        assert_decoded(decode_all(BODY, &limits).unwrap());
+        assert_decoded(feed_all(&[BODY], &limits).unwrap());
+    }
+
+    #[test]
+    fn decodes_input_split_at_every_offset() {
+        let limits = config::RequestLimits::default();
+        for at in 0..=BODY.len() {
+            assert_decoded(feed_all(&[&BODY[..at], &BODY[at..]], &limits).unwrap());
+        }
+        let bytes: Vec<&[u8]> = BODY.chunks(1).collect();
+        assert_decoded(feed_all(&bytes, &limits).unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_size_lines() {
+        let limits = config::RequestLimits::default();
+        let too_long = format!(
+            "5;{}\r\nhello\r\n0\r\n\r\n",
+            "a".repeat(MAX_CHUNK_SIZE_LINE_LENGTH)
+        );
+        let overflowing = format!("{}\r\nhello\r\n0\r\n\r\n", "F".repeat(17));
+        for input in [
+            too_long.as_str(),
+            overflowing.as_str(),
+            "+5\r\nhello\r\n0\r\n\r\n",
+            "\r\nhello\r\n0\r\n\r\n",
+            "hello\r\n0\r\n\r\n",
+        ] {
+            for result in [
+                decode(&mut input.as_bytes(), &limits),
+                feed_all(&[input.as_bytes()], &limits),
+            ] {
+                match result {
+                    Err(WebServerError::RequestParseError(
+                        RequestError::InvalidChunkedBodyError(_),
+                    )) => {}
+                    other => panic!("{:?} was not rejected: {:?}", input, other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_body_over_the_limit() {
+        let limits = config::RequestLimits {
+            max_body_size: 10,
+            ..config::RequestLimits::default()
+        };
+        // the second chunk would take the body one byte past the limit
+        let input = b"5\r\nhello\r\n6\r\n, worl\r\n0\r\n\r\n";
+        for result in [
+            decode(&mut &input[..], &limits),
+            feed_all(&[input], &limits),
+        ] {
+            assert!(matches!(
+                result,
+                Err(WebServerError::RequestParseError(
+                    RequestError::PayloadTooLargeError(10)
+                ))
+            ));
+        }
+        // right at the limit it is decoded
+        let input = b"5\r\nhello\r\n5\r\n, wor\r\n0\r\n\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(decode_all(input, &limits).unwrap().0, b"hello, wor");
+    }
+
+    #[test]
+    fn rejects_chunk_data_without_its_line_break() {
+        let limits = config::RequestLimits::default();
+        for input in [
+            &b"5\r\nhelloX\r\n0\r\n\r\n"[..],
+            &b"5\r\nhello0\r\n\r\n"[..],
+        ] {
+            for result in [
+                decode(&mut &input[..], &limits),
+                feed_all(&[input], &limits),
+            ] {
+                match result {
+                    Err(WebServerError::RequestParseError(
+                        RequestError::InvalidChunkedBodyError(_),
+                    )) => {}
+                    other => panic!("{:?} was not rejected: {:?}", input, other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fails_on_a_body_cut_short() {
+        let limits = config::RequestLimits::default();
+        match decode(&mut &b"5\r\nhel"[..], &limits) {
+            Err(WebServerError::IO(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("a body cut short was decoded: {:?}", other),
+        }
+        // the decoder waits for the rest instead
+        let mut decoder = ChunkedDecoder::default();
+        assert_eq!(decoder.feed(b"5\r\nhel", &limits).unwrap(), 6);
+        assert!(!decoder.is_done());
+    }
+}
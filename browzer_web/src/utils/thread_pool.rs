@@ -1,11 +1,16 @@
-use uuid::Uuid;
 use crate::error::*;
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread::{self},
+    time::{Duration, Instant},
 };
+use uuid::Uuid;
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
 
 #[derive(Debug)]
 pub struct Worker {
@@ -14,7 +19,11 @@ pub struct Worker {
 }
 
 impl Worker {
-    pub fn new(id: Uuid, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    pub fn new(
+        id: Uuid,
+        receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+        pending: Arc<AtomicUsize>,
+    ) -> Worker {
         let thread = thread::spawn(move || loop {
             let message = receiver
                 .lock()
@@ -22,7 +31,12 @@ impl Worker {
                 .and_then(|rx| rx.recv().map_err(ThreadPoolError::from));
             match message {
                 Ok(job) => {
-                    job();
+                    // a panicking job (e.g. a handler panicking) is done with all the same, the
+                    // worker goes on with the next one
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        eprintln!("Worker {} recovered from a panicking job", id);
+                    }
+                    pending.fetch_sub(1, Ordering::SeqCst);
                 }
                 Err(_) => {
                     println!("Worker {} disconnected, shutting down...", id.to_string());
@@ -39,50 +53,183 @@ impl Worker {
     }
 }
 
-// The thread pool maintains a set of workers and a channel for sending jobs to them.
+// The sending half of the job channel, a bounded channel makes `execute` block while the queue
+// is full
+#[derive(Debug)]
+enum JobSender {
+    Unbounded(mpsc::Sender<Job>),
+    Bounded(mpsc::SyncSender<Job>),
+}
+
+// The thread pool maintains a set of workers and a channel for sending jobs to them. All of its
+// methods take `&self`, so that it can be shared (e.g. in an `Arc`) by the threads sending jobs
 #[derive(Debug)]
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    workers: Mutex<Vec<Worker>>,
+    sender: Mutex<Option<JobSender>>,
+    size: usize,
+    queue_size: Option<usize>,
+    // number of jobs that are either queued or being run by a worker
+    pending: Arc<AtomicUsize>,
 }
 impl ThreadPool {
     pub fn new(size: usize) -> ThreadPool {
+        return ThreadPool::with_queue_size(size, None);
+    }
+
+    // This function creates a pool whose queue holds at most `queue_size` jobs waiting for a
+    // free worker, `None` makes the queue unbounded
+    pub fn with_queue_size(size: usize, queue_size: Option<usize>) -> ThreadPool {
         assert!(size > 0);
 
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = match queue_size {
+            Some(queue_size) => {
+                let (sender, receiver) = mpsc::sync_channel(queue_size);
+                (JobSender::Bounded(sender), receiver)
+            }
+            None => {
+                let (sender, receiver) = mpsc::channel();
+                (JobSender::Unbounded(sender), receiver)
+            }
+        };
         let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new(AtomicUsize::new(0));
 
         let mut workers = Vec::with_capacity(size);
         for _ in 0..size {
-            workers.push(Worker::new(Uuid::new_v4(), Arc::clone(&receiver)));
+            workers.push(Worker::new(
+                Uuid::new_v4(),
+                Arc::clone(&receiver),
+                Arc::clone(&pending),
+            ));
         }
 
         // return the ThreadPool struct
         return ThreadPool {
-            sender: Some(sender),
-            workers,
+            sender: Mutex::new(Some(sender)),
+            workers: Mutex::new(workers),
+            size,
+            queue_size,
+            pending,
         };
     }
 
+    // This function sends a job to the workers, if the queue is bounded and full it blocks until
+    // a worker takes a job off the queue
     pub fn execute<F>(&self, f: F) -> Result<(), ThreadPoolError>
     where
         F: FnOnce() + Send + 'static,
     {
-        let _ = self
-            .sender
+        let sender = self.sender.lock()?;
+        let sender = sender
             .as_ref()
-            .ok_or_else(|| ThreadPoolError::SendError("Sender is not innitialized".to_string()))?
-            .send(Box::new(f))
-            .map_err(|e| ThreadPoolError::SendError(e.to_string()));
-        Ok(())
+            .ok_or_else(|| ThreadPoolError::SendError("Sender is not innitialized".to_string()))?;
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let result = match sender {
+            JobSender::Unbounded(sender) => sender
+                .send(Box::new(f))
+                .map_err(|e| ThreadPoolError::SendError(e.to_string())),
+            JobSender::Bounded(sender) => sender
+                .send(Box::new(f))
+                .map_err(|e| ThreadPoolError::SendError(e.to_string())),
+        };
+        if result.is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+        return result;
+    }
+
+    // This function sends a job to the workers like `execute`, but without ever blocking: if the
+    // queue is bounded and full the job is handed back, to be sent again once a worker is free
+    pub fn try_execute(&self, job: Job) -> Result<Option<Job>, ThreadPoolError> {
+        let sender = self.sender.lock()?;
+        let sender = sender
+            .as_ref()
+            .ok_or_else(|| ThreadPoolError::SendError("Sender is not innitialized".to_string()))?;
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let result = match sender {
+            JobSender::Unbounded(sender) => match sender.send(job) {
+                Ok(_) => Ok(None),
+                Err(e) => Err(ThreadPoolError::SendError(e.to_string())),
+            },
+            JobSender::Bounded(sender) => match sender.try_send(job) {
+                Ok(_) => Ok(None),
+                Err(mpsc::TrySendError::Full(job)) => {
+                    self.pending.fetch_sub(1, Ordering::SeqCst);
+                    return Ok(Some(job));
+                }
+                Err(e) => Err(ThreadPoolError::SendError(e.to_string())),
+            },
+        };
+        if result.is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+        return result;
+    }
+
+    // This function returns whether every worker is busy and the queue is full, i.e. whether
+    // `execute` would block right now. A pool with an unbounded queue is never saturated
+    pub fn is_saturated(&self) -> bool {
+        match self.queue_size {
+            Some(queue_size) => {
+                return self.pending.load(Ordering::SeqCst) >= self.size + queue_size;
+            }
+            None => return false,
+        }
+    }
+
+    // This function shuts the pool down, the workers finish the jobs that are already queued and
+    // then exit. It waits at most `timeout` for them to do so and returns the number of workers
+    // which were still busy when the timeout ran out, those are detached and left running.
+    pub fn shutdown(&self, timeout: Duration) -> usize {
+        match self.sender.lock() {
+            Ok(mut sender) => drop(sender.take()),
+            Err(poisoned) => drop(poisoned.into_inner().take()),
+        };
+        let mut workers = match self.workers.lock() {
+            Ok(workers) => workers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline
+            && workers.iter().any(|worker| match &worker.thread {
+                Some(thread) => !thread.is_finished(),
+                None => false,
+            })
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut busy_workers = 0;
+        for worker in workers.iter_mut() {
+            if let Some(thread) = worker.thread.take() {
+                match thread.is_finished() {
+                    true => {
+                        let _ = thread.join();
+                    }
+                    false => {
+                        println!("Worker {} is still busy, detaching it", worker.id);
+                        busy_workers += 1;
+                    }
+                }
+            }
+        }
+        return busy_workers;
     }
 }
 
 // graceful shutdown
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
-        for worker in &mut self.workers {
+        if let Ok(sender) = self.sender.get_mut() {
+            drop(sender.take());
+        }
+        let workers = match self.workers.get_mut() {
+            Ok(workers) => workers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for worker in workers {
             println!("Shuting down worker {}", worker.id.to_string());
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
@@ -0,0 +1,851 @@
+use crate::{connection, context, error, response, utils};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// the GUID a handshake key is combined with to build `Sec-WebSocket-Accept` (RFC 6455 section 1.3)
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// the only version of the protocol there is
+const VERSION: &str = "13";
+// control frames (close, ping and pong) carry at most this many bytes
+const MAX_CONTROL_PAYLOAD: usize = 125;
+// how often a read waiting for the next frame checks whether the server is shutting down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// status codes a connection is closed with (RFC 6455 section 7.4.1)
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+// A complete message received on or sent over a `WebSocket`, a message sent by the client in
+// several fragments is put back together before it is returned
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    // a ping is answered with a pong by the `WebSocket` itself, it is only returned to let the
+    // handler know about it
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+// The status code and the reason a connection is closed with
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// The server end of a WebSocket connection (RFC 6455), handed to the handler registered with
+// `WebServer::websocket` once the handshake is done.
+//
+// `read` blocks until the next message arrives, and the `send*` methods write a message right
+// away. The connection is closed by sending a close frame with `close` and then reading until the
+// client's close frame comes back as `Message::Close`, a close frame the client starts with is
+// answered automatically. A `WebSocket` dropped without being closed sends a close frame with
+// `CLOSE_NORMAL`.
+//
+// A message larger than `RequestLimits::max_body_size` fails the connection with
+// `CLOSE_MESSAGE_TOO_BIG`, and once the server starts shutting down a `read` waiting for the next
+// message closes it with `CLOSE_GOING_AWAY`
+#[derive(Debug)]
+pub struct WebSocket {
+    stream: BufReader<connection::Upgraded>,
+    read_timeout: Option<Duration>,
+    max_message_size: usize,
+    // the opcode and the payload so far of a message the client is sending in fragments
+    fragments: Option<(u8, Vec<u8>)>,
+    // set once a close frame was sent, no other frame may follow it
+    close_sent: bool,
+    // set once nothing is read off the connection anymore, because the client sent a close frame
+    // or the connection failed
+    closed: bool,
+}
+
+// This function answers a WebSocket handshake request (RFC 6455 section 4.2) with `101 Switching
+// Protocols`, the connection is handed to `handler` as a `WebSocket` along with the context of
+// the request once the response is written. A request which is not a valid handshake gets
+// `426 Upgrade Required` (or `400 Bad Request` for a malformed key) instead
+pub(crate) fn accept<F>(context: context::Context, handler: Arc<F>) -> response::Response
+where
+    F: Fn(context::Context, WebSocket) + 'static + Send + Sync,
+{
+    let request = &context.request;
+    let header_has = |name: &str, token: &str| match request.header(name) {
+        Some(value) => value
+            .split(',')
+            .any(|part| part.trim().eq_ignore_ascii_case(token)),
+        None => false,
+    };
+
+    if !matches!(request.method, utils::HttpMethod::GET)
+        || request.version != "HTTP/1.1"
+        || !header_has("Upgrade", "websocket")
+        || !header_has("Connection", "upgrade")
+    {
+        let status_code = utils::HttpStatusCode::UpgradeRequired;
+        let mut response =
+            response::Response::new(status_code.clone(), status_code.code().0.to_string());
+        response
+            .headers
+            .insert("Upgrade".to_string(), "websocket".to_string());
+        return response;
+    }
+    match request.header("Sec-WebSocket-Version") {
+        Some(version) if version.trim() == VERSION => {}
+        _ => {
+            let status_code = utils::HttpStatusCode::UpgradeRequired;
+            let mut response =
+                response::Response::new(status_code.clone(), status_code.code().0.to_string());
+            response
+                .headers
+                .insert("Sec-WebSocket-Version".to_string(), VERSION.to_string());
+            return response;
+        }
+    };
+    // the key is 16 random bytes in base64
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) => key.trim().to_string(),
+        None => String::new(),
+    };
+    match STANDARD.decode(&key) {
+        Ok(decoded) if decoded.len() == 16 => {}
+        _ => {
+            let status_code = utils::HttpStatusCode::BadRequest;
+            return response::Response::new(status_code.clone(), status_code.code().0.to_string());
+        }
+    };
+
+    let mut response = response::Response::new(utils::HttpStatusCode::SwitchingProtocols, "");
+    response
+        .headers
+        .insert("Upgrade".to_string(), "websocket".to_string());
+    response
+        .headers
+        .insert("Connection".to_string(), "Upgrade".to_string());
+    response
+        .headers
+        .insert("Sec-WebSocket-Accept".to_string(), accept_key(&key));
+    response.upgrade = Some(response::Upgrade::new(move |upgraded| {
+        (handler)(context, WebSocket::new(upgraded));
+    }));
+    return response;
+}
+
+// This function computes the `Sec-WebSocket-Accept` that proves to the client that the server
+// understood its handshake
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    return STANDARD.encode(hasher.finalize());
+}
+
+impl WebSocket {
+    pub fn new(upgraded: connection::Upgraded) -> WebSocket {
+        let max_message_size = upgraded.max_message_size;
+        return WebSocket {
+            stream: BufReader::new(upgraded),
+            read_timeout: None,
+            max_message_size,
+            fragments: None,
+            close_sent: false,
+            closed: false,
+        };
+    }
+
+    pub fn get_ref(&self) -> &connection::Upgraded {
+        return self.stream.get_ref();
+    }
+
+    // This function bounds how long `read` waits for the next message, `None` (the default) waits
+    // until the client sends one or closes the connection
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    // This function reads the next message off the connection, it fails with
+    // `WebSocketError::ConnectionClosed` once the connection is closed
+    pub fn read(&mut self) -> Result<Message, error::WebSocketError> {
+        loop {
+            if self.closed {
+                return Err(error::WebSocketError::ConnectionClosed);
+            }
+            let frame = self.read_frame()?;
+
+            // after a close frame was sent only the client's close frame matters
+            if self.close_sent && frame.opcode != OPCODE_CLOSE {
+                continue;
+            }
+            match frame.opcode {
+                OPCODE_TEXT | OPCODE_BINARY => {
+                    if self.fragments.is_some() {
+                        return Err(self.fail(
+                            CLOSE_PROTOCOL_ERROR,
+                            error::WebSocketError::ProtocolError(
+                                "a new message started before the fragmented one ended".to_string(),
+                            ),
+                        ));
+                    }
+                    if frame.fin {
+                        return self.message(frame.opcode, frame.payload);
+                    }
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+                OPCODE_CONTINUATION => {
+                    let (opcode, mut payload) = match self.fragments.take() {
+                        Some(fragments) => fragments,
+                        None => {
+                            return Err(self.fail(
+                                CLOSE_PROTOCOL_ERROR,
+                                error::WebSocketError::ProtocolError(
+                                    "a continuation frame without a message to continue"
+                                        .to_string(),
+                                ),
+                            ));
+                        }
+                    };
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return Err(self.fail(
+                            CLOSE_MESSAGE_TOO_BIG,
+                            error::WebSocketError::MessageTooBigError(self.max_message_size),
+                        ));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return self.message(opcode, payload);
+                    }
+                    self.fragments = Some((opcode, payload));
+                }
+                OPCODE_PING => {
+                    self.write_frame(OPCODE_PONG, &frame.payload)?;
+                    return Ok(Message::Ping(frame.payload));
+                }
+                OPCODE_PONG => return Ok(Message::Pong(frame.payload)),
+                OPCODE_CLOSE => return self.close_received(frame.payload),
+                opcode => {
+                    return Err(self.fail(
+                        CLOSE_PROTOCOL_ERROR,
+                        error::WebSocketError::ProtocolError(format!("unknown opcode {}", opcode)),
+                    ));
+                }
+            };
+        }
+    }
+
+    // This function sends a message, nothing can be sent anymore once a close frame was sent
+    pub fn send(&mut self, message: Message) -> Result<(), error::WebSocketError> {
+        if self.close_sent {
+            return Err(error::WebSocketError::ConnectionClosed);
+        }
+        match message {
+            Message::Text(text) => return self.write_frame(OPCODE_TEXT, text.as_bytes()),
+            Message::Binary(data) => return self.write_frame(OPCODE_BINARY, &data),
+            Message::Ping(data) => return self.write_control_frame(OPCODE_PING, &data),
+            Message::Pong(data) => return self.write_control_frame(OPCODE_PONG, &data),
+            Message::Close(frame) => {
+                let mut payload = Vec::new();
+                if let Some(frame) = frame {
+                    payload.extend_from_slice(&frame.code.to_be_bytes());
+                    payload.extend_from_slice(frame.reason.as_bytes());
+                }
+                self.write_control_frame(OPCODE_CLOSE, &payload)?;
+                self.close_sent = true;
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), error::WebSocketError> {
+        return self.send(Message::Text(text.to_string()));
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), error::WebSocketError> {
+        return self.send(Message::Binary(data.to_vec()));
+    }
+
+    pub fn ping(&mut self, data: &[u8]) -> Result<(), error::WebSocketError> {
+        return self.send(Message::Ping(data.to_vec()));
+    }
+
+    // This function starts closing the connection, the client answers with a close frame of its
+    // own which `read` returns as `Message::Close`
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), error::WebSocketError> {
+        return self.send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.to_string(),
+        })));
+    }
+
+    // This function turns the payload of a complete data message into a `Message`
+    fn message(&mut self, opcode: u8, payload: Vec<u8>) -> Result<Message, error::WebSocketError> {
+        if opcode == OPCODE_BINARY {
+            return Ok(Message::Binary(payload));
+        }
+        match String::from_utf8(payload) {
+            Ok(text) => return Ok(Message::Text(text)),
+            Err(_) => {
+                return Err(self.fail(CLOSE_INVALID_DATA, error::WebSocketError::InvalidUtf8Error))
+            }
+        }
+    }
+
+    // This function handles a close frame from the client, it is answered with a close frame
+    // carrying the same status code unless the server started closing the connection itself
+    fn close_received(&mut self, payload: Vec<u8>) -> Result<Message, error::WebSocketError> {
+        let frame = match payload.len() {
+            0 => None,
+            1 => {
+                return Err(self.fail(
+                    CLOSE_PROTOCOL_ERROR,
+                    error::WebSocketError::ProtocolError("a close frame of 1 byte".to_string()),
+                ));
+            }
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                // codes outside of these ranges are reserved and must not be sent
+                if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+                    return Err(self.fail(
+                        CLOSE_PROTOCOL_ERROR,
+                        error::WebSocketError::ProtocolError(format!(
+                            "invalid close status code {}",
+                            code
+                        )),
+                    ));
+                }
+                let reason = match String::from_utf8(payload[2..].to_vec()) {
+                    Ok(reason) => reason,
+                    Err(_) => {
+                        return Err(
+                            self.fail(CLOSE_INVALID_DATA, error::WebSocketError::InvalidUtf8Error)
+                        );
+                    }
+                };
+                Some(CloseFrame { code, reason })
+            }
+        };
+
+        self.closed = true;
+        if !self.close_sent {
+            let payload = match &frame {
+                Some(frame) => frame.code.to_be_bytes().to_vec(),
+                None => Vec::new(),
+            };
+            self.close_sent = true;
+            self.write_frame(OPCODE_CLOSE, &payload)?;
+        }
+        return Ok(Message::Close(frame));
+    }
+
+    // This function fails the connection (RFC 6455 section 7.1.7), a close frame with `code` is
+    // sent if possible and nothing is read off the connection anymore
+    fn fail(&mut self, code: u16, e: error::WebSocketError) -> error::WebSocketError {
+        self.closed = true;
+        self.fragments = None;
+        if !self.close_sent {
+            self.close_sent = true;
+            let _ = self.write_frame(OPCODE_CLOSE, &code.to_be_bytes());
+        }
+        return e;
+    }
+
+    // This function waits for the first byte of the next frame in short slices, so that a
+    // shutdown of the server is noticed while the client is quiet
+    fn wait_for_frame(&mut self) -> Result<(), error::WebSocketError> {
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if self.stream.get_ref().is_shutting_down() {
+                return Err(self.fail(CLOSE_GOING_AWAY, error::WebSocketError::ConnectionClosed));
+            }
+            let mut slice = SHUTDOWN_POLL_INTERVAL;
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(error::WebSocketError::IO(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "no message arrived in time",
+                    )));
+                }
+                slice = slice.min(remaining);
+            }
+            self.stream.get_mut().set_read_timeout(Some(slice));
+            match self.stream.fill_buf() {
+                Ok([]) => {
+                    // the client went away without closing the connection
+                    self.closed = true;
+                    return Err(error::WebSocketError::ConnectionClosed);
+                }
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => {
+                    self.closed = true;
+                    return Err(error::WebSocketError::IO(e));
+                }
+            };
+        }
+        // the rest of the frame has to follow within the read timeout
+        self.stream.get_mut().set_read_timeout(self.read_timeout);
+        return Ok(());
+    }
+
+    // This function reads a single frame (RFC 6455 section 5.2) and unmasks its payload
+    fn read_frame(&mut self) -> Result<Frame, error::WebSocketError> {
+        self.wait_for_frame()?;
+
+        let mut head = [0; 2];
+        self.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        if head[0] & 0x70 != 0 {
+            return Err(self.fail(
+                CLOSE_PROTOCOL_ERROR,
+                error::WebSocketError::ProtocolError("reserved bits are set".to_string()),
+            ));
+        }
+        // every frame a client sends has to be masked
+        if head[1] & 0x80 == 0 {
+            return Err(self.fail(
+                CLOSE_PROTOCOL_ERROR,
+                error::WebSocketError::ProtocolError("the frame is not masked".to_string()),
+            ));
+        }
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                self.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0; 8];
+                self.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+        if opcode >= OPCODE_CLOSE && (!fin || length > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(self.fail(
+                CLOSE_PROTOCOL_ERROR,
+                error::WebSocketError::ProtocolError(
+                    "a control frame is fragmented or too long".to_string(),
+                ),
+            ));
+        }
+        if length > self.max_message_size as u64 {
+            return Err(self.fail(
+                CLOSE_MESSAGE_TOO_BIG,
+                error::WebSocketError::MessageTooBigError(self.max_message_size),
+            ));
+        }
+
+        let mut mask = [0; 4];
+        self.read_exact(&mut mask)?;
+        let mut payload = Vec::new();
+        match self.stream.by_ref().take(length).read_to_end(&mut payload) {
+            Ok(n) if n as u64 == length => {}
+            Ok(_) => {
+                self.closed = true;
+                return Err(error::WebSocketError::IO(io::Error::from(
+                    io::ErrorKind::UnexpectedEof,
+                )));
+            }
+            Err(e) => {
+                self.closed = true;
+                return Err(error::WebSocketError::IO(e));
+            }
+        };
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        return Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        });
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), error::WebSocketError> {
+        match self.stream.read_exact(buf) {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                self.closed = true;
+                return Err(error::WebSocketError::IO(e));
+            }
+        }
+    }
+
+    fn write_control_frame(
+        &mut self,
+        opcode: u8,
+        payload: &[u8],
+    ) -> Result<(), error::WebSocketError> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(error::WebSocketError::ProtocolError(format!(
+                "control frames carry at most {} bytes",
+                MAX_CONTROL_PAYLOAD
+            )));
+        }
+        return self.write_frame(opcode, payload);
+    }
+
+    // This function writes a single unfragmented frame, frames sent by the server are not masked
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), error::WebSocketError> {
+        let mut head = vec![0x80 | opcode];
+        match payload.len() {
+            length if length < 126 => head.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                head.push(126);
+                head.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                head.push(127);
+                head.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        };
+        let stream = self.stream.get_mut();
+        match stream
+            .write_all(&head)
+            .and_then(|_| stream.write_all(payload))
+            .and_then(|_| stream.flush())
+        {
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(error::WebSocketError::IO(e)),
+        }
+    }
+}
+
+// a handler which is done with the connection without closing it closes it normally
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        if !self.close_sent && !self.closed {
+            let _ = self.close(CLOSE_NORMAL, "");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown;
+    use std::net::{TcpListener, TcpStream};
+
+    // the masking key of the examples in RFC 6455 section 5.7
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    // This function returns a `WebSocket` served over a loopback connection along with the client
+    // end of that connection
+    fn connected(max_message_size: usize) -> (WebSocket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let upgraded = connection::Upgraded::new(
+            connection::TimedStream::new(connection::Stream::Tcp(server)),
+            Vec::new(),
+            max_message_size,
+            Arc::new(shutdown::ShutdownState::default()),
+        );
+        return (WebSocket::new(upgraded), client);
+    }
+
+    // This function builds a frame the way a client sends it, masked with `MASK`
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![(fin as u8) << 7 | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(0x80 | length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        };
+        frame.extend_from_slice(&MASK);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ MASK[i % 4]),
+        );
+        return frame;
+    }
+
+    // This function reads a frame sent by the server off the client end, returning its head and
+    // its payload
+    fn read_server_frame(client: &mut TcpStream) -> (Vec<u8>, Vec<u8>) {
+        let mut head = vec![0; 2];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(head[1] & 0x80, 0, "the server masked a frame");
+        let length = match head[1] {
+            126 => {
+                let mut length = [0; 2];
+                client.read_exact(&mut length).unwrap();
+                head.extend_from_slice(&length);
+                u16::from_be_bytes(length) as usize
+            }
+            127 => {
+                let mut length = [0; 8];
+                client.read_exact(&mut length).unwrap();
+                head.extend_from_slice(&length);
+                u64::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        client.read_exact(&mut payload).unwrap();
+        return (head, payload);
+    }
+
+    // This function checks that the connection was failed with a close frame carrying `code`
+    fn assert_failed_with(client: &mut TcpStream, code: u16) {
+        let (head, payload) = read_server_frame(client);
+        assert_eq!(head, [0x80 | OPCODE_CLOSE, 2]);
+        assert_eq!(payload, code.to_be_bytes());
+    }
+
+    #[test]
+    fn hashes_the_handshake_key() {
+        // the sample handshake of RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn reads_a_masked_text_message() {
+        let (mut websocket, mut client) = connected(1024);
+        client
+            .write_all(&[
+                0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+            ])
+            .unwrap();
+        assert_eq!(
+            websocket.read().unwrap(),
+            Message::Text("Hello".to_string())
+        );
+    }
+
+    #[test]
+    fn answers_a_ping_with_an_unmasked_pong() {
+        let (mut websocket, mut client) = connected(1024);
+        client
+            .write_all(&client_frame(true, OPCODE_PING, b"Hello"))
+            .unwrap();
+        assert_eq!(websocket.read().unwrap(), Message::Ping(b"Hello".to_vec()));
+        let (head, payload) = read_server_frame(&mut client);
+        assert_eq!(head, [0x8a, 0x05]);
+        assert_eq!(payload, b"Hello");
+    }
+
+    #[test]
+    fn fails_on_an_unmasked_frame() {
+        let (mut websocket, mut client) = connected(1024);
+        client
+            .write_all(&[0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f])
+            .unwrap();
+        assert!(matches!(
+            websocket.read(),
+            Err(error::WebSocketError::ProtocolError(_))
+        ));
+        assert_failed_with(&mut client, CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn reads_and_writes_16_and_64_bit_lengths() {
+        let (mut websocket, mut client) = connected(128 * 1024);
+        for length in [125, 126, 256, u16::MAX as usize, 64 * 1024] {
+            let data = vec![0xab; length];
+            let frame = client_frame(true, OPCODE_BINARY, &data);
+            client.write_all(&frame).unwrap();
+            assert_eq!(websocket.read().unwrap(), Message::Binary(data.clone()));
+
+            websocket.send_binary(&data).unwrap();
+            // the server sends the same head, only unmasked
+            let mut expected = frame[..frame.len() - length - MASK.len()].to_vec();
+            expected[1] &= 0x7F;
+            let (head, payload) = read_server_frame(&mut client);
+            assert_eq!(head, expected);
+            assert_eq!(payload, data);
+        }
+        // the examples of RFC 6455 section 5.7
+        websocket.send_binary(&[0; 256]).unwrap();
+        assert_eq!(read_server_frame(&mut client).0, [0x82, 0x7E, 0x01, 0x00]);
+        websocket.send_binary(&[0; 65536]).unwrap();
+        assert_eq!(
+            read_server_frame(&mut client).0,
+            [0x82, 0x7F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn fails_on_a_message_over_the_limit() {
+        let (mut websocket, mut client) = connected(16);
+        client
+            .write_all(&client_frame(true, OPCODE_BINARY, &[0; 17]))
+            .unwrap();
+        assert!(matches!(
+            websocket.read(),
+            Err(error::WebSocketError::MessageTooBigError(16))
+        ));
+        assert_failed_with(&mut client, CLOSE_MESSAGE_TOO_BIG);
+    }
+
+    #[test]
+    fn caps_the_size_of_control_frames() {
+        let (mut websocket, mut client) = connected(1024);
+        assert!(matches!(
+            websocket.ping(&[0; 126]),
+            Err(error::WebSocketError::ProtocolError(_))
+        ));
+        websocket.ping(&[0; 125]).unwrap();
+        assert_eq!(read_server_frame(&mut client).0, [0x89, 125]);
+
+        client
+            .write_all(&client_frame(true, OPCODE_PING, &[0; 126]))
+            .unwrap();
+        assert!(matches!(
+            websocket.read(),
+            Err(error::WebSocketError::ProtocolError(_))
+        ));
+        assert_failed_with(&mut client, CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn fails_on_a_fragmented_control_frame() {
+        let (mut websocket, mut client) = connected(1024);
+        client
+            .write_all(&client_frame(false, OPCODE_PING, b"Hel"))
+            .unwrap();
+        assert!(matches!(
+            websocket.read(),
+            Err(error::WebSocketError::ProtocolError(_))
+        ));
+        assert_failed_with(&mut client, CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn puts_a_fragmented_message_back_together() {
+        let (mut websocket, mut client) = connected(1024);
+        // a control frame may come in between the fragments
+        let mut frames = client_frame(false, OPCODE_TEXT, b"Hel");
+        frames.extend(client_frame(true, OPCODE_PING, b""));
+        frames.extend(client_frame(false, OPCODE_CONTINUATION, b"l"));
+        frames.extend(client_frame(true, OPCODE_CONTINUATION, b"o"));
+        client.write_all(&frames).unwrap();
+        assert_eq!(websocket.read().unwrap(), Message::Ping(Vec::new()));
+        assert_eq!(
+            websocket.read().unwrap(),
+            Message::Text("Hello".to_string())
+        );
+    }
+
+    #[test]
+    fn fails_on_fragments_out_of_order() {
+        for frames in [
+            client_frame(true, OPCODE_CONTINUATION, b"lo"),
+            [
+                client_frame(false, OPCODE_TEXT, b"Hel"),
+                client_frame(true, OPCODE_TEXT, b"lo"),
+            ]
+            .concat(),
+        ] {
+            let (mut websocket, mut client) = connected(1024);
+            client.write_all(&frames).unwrap();
+            assert!(matches!(
+                websocket.read(),
+                Err(error::WebSocketError::ProtocolError(_))
+            ));
+            assert_failed_with(&mut client, CLOSE_PROTOCOL_ERROR);
+        }
+    }
+
+    #[test]
+    fn fails_a_fragmented_message_over_the_limit() {
+        let (mut websocket, mut client) = connected(4);
+        let mut frames = client_frame(false, OPCODE_TEXT, b"Hel");
+        frames.extend(client_frame(true, OPCODE_CONTINUATION, b"lo"));
+        client.write_all(&frames).unwrap();
+        assert!(matches!(
+            websocket.read(),
+            Err(error::WebSocketError::MessageTooBigError(4))
+        ));
+        assert_failed_with(&mut client, CLOSE_MESSAGE_TOO_BIG);
+    }
+
+    #[test]
+    fn answers_a_close_frame_with_a_valid_code() {
+        for code in [1000, 1003, 1007, 1014, 3000, 4999] {
+            let (mut websocket, mut client) = connected(1024);
+            let mut payload = u16::to_be_bytes(code).to_vec();
+            payload.extend_from_slice(b"bye");
+            client
+                .write_all(&client_frame(true, OPCODE_CLOSE, &payload))
+                .unwrap();
+            assert_eq!(
+                websocket.read().unwrap(),
+                Message::Close(Some(CloseFrame {
+                    code,
+                    reason: "bye".to_string(),
+                }))
+            );
+            assert_failed_with(&mut client, code);
+            assert!(matches!(
+                websocket.read(),
+                Err(error::WebSocketError::ConnectionClosed)
+            ));
+        }
+    }
+
+    #[test]
+    fn answers_an_empty_close_frame() {
+        let (mut websocket, mut client) = connected(1024);
+        client
+            .write_all(&client_frame(true, OPCODE_CLOSE, b""))
+            .unwrap();
+        assert_eq!(websocket.read().unwrap(), Message::Close(None));
+        assert_eq!(read_server_frame(&mut client), (vec![0x88, 0], Vec::new()));
+    }
+
+    #[test]
+    fn fails_on_an_invalid_close_frame() {
+        let mut frames = Vec::new();
+        // reserved, not to be sent or out of range codes
+        for code in [0u16, 999, 1004, 1005, 1006, 1015, 2999, 5000] {
+            frames.push((code.to_be_bytes().to_vec(), CLOSE_PROTOCOL_ERROR));
+        }
+        frames.push((vec![0x03], CLOSE_PROTOCOL_ERROR));
+        frames.push((vec![0x03, 0xe8, 0xff], CLOSE_INVALID_DATA));
+        for (payload, code) in frames {
+            let (mut websocket, mut client) = connected(1024);
+            client
+                .write_all(&client_frame(true, OPCODE_CLOSE, &payload))
+                .unwrap();
+            assert!(websocket.read().is_err(), "{:?} was taken", payload);
+            assert_failed_with(&mut client, code);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// What the integration tests share: running a `WebServer` on a background thread, in each of the
+// ways it can serve connections, and talking raw HTTP/1.1 to it
+#![allow(dead_code)]
+
+use browzer_web::{builder::WebServerBuilder, shutdown::ServerHandle, WebServer};
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// How the server under test serves its connections
+#[derive(Debug, Clone, Copy)]
+pub enum Mode {
+    // on the worker threads, one connection per worker
+    Blocking,
+    // on an I/O thread, with the handlers on the worker threads
+    IoThreads,
+    // on a tokio runtime, see `WebServer::into_async`
+    #[cfg(feature = "tokio")]
+    Async,
+}
+
+pub const MODES: &[Mode] = &[
+    Mode::Blocking,
+    Mode::IoThreads,
+    #[cfg(feature = "tokio")]
+    Mode::Async,
+];
+
+// A server listening on a port of its own, it is shut down when this is dropped
+pub struct TestServer {
+    pub address: SocketAddr,
+    handle: ServerHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// This function builds the server set up by `builder` on a free port of the loopback interface,
+// registers its routes with `routes` and starts it in the given `mode`
+pub fn serve<F>(mode: Mode, builder: WebServerBuilder, routes: F) -> TestServer
+where
+    F: FnOnce(&mut WebServer),
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let io_threads = match mode {
+        Mode::IoThreads => 1,
+        _ => 0,
+    };
+    let mut server = builder
+        .listener(listener)
+        .io_threads(io_threads)
+        .hide_banner(true)
+        .build()
+        .unwrap();
+    routes(&mut server);
+
+    let (handle, thread) = match mode {
+        #[cfg(feature = "tokio")]
+        Mode::Async => {
+            let mut server = server.into_async().unwrap();
+            let handle = server.handle();
+            let thread = thread::spawn(move || {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(server.listen());
+            });
+            (handle, thread)
+        }
+        _ => {
+            let handle = server.handle();
+            (handle, thread::spawn(move || server.listen()))
+        }
+    };
+    return TestServer {
+        address,
+        handle,
+        thread: Some(thread),
+    };
+}
+
+// This function opens a connection to `address` whose reads give up after a few seconds, so that
+// a server which does not answer fails the test instead of hanging it
+pub fn connect(address: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    return stream;
+}
+
+// This function sends `request` as is on a new connection and returns everything the server
+// sends back until it closes the connection
+pub fn exchange(address: SocketAddr, request: &[u8]) -> io::Result<String> {
+    let mut stream = connect(address);
+    stream.write_all(request)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    return Ok(String::from_utf8_lossy(&response).to_string());
+}
@@ -0,0 +1,148 @@
+// The methods a route was not registered for are answered by the server itself: `HEAD` by the
+// `GET` handler without its body, `OPTIONS` with the methods the path allows, another method with
+// `405 Method Not Allowed` and a method no route knows about with `501 Not Implemented`
+mod common;
+
+use browzer_web::{
+    utils::{HttpMethod, HttpStatusCode},
+    WebServer,
+};
+use common::{exchange, serve, MODES};
+
+fn serve_routes(server: &mut WebServer) {
+    server.get("/items", |mut c| c.send_string(HttpStatusCode::OK, "items"));
+    server.post("/items", |mut c| {
+        c.send_string(HttpStatusCode::Created, "created")
+    });
+    server.method(
+        HttpMethod::Extension("PURGE".to_string()),
+        "/cache",
+        |mut c| c.send_string(HttpStatusCode::OK, "purged"),
+    );
+}
+
+// This function sends a single `method` request for `path` and returns the response
+fn request(address: std::net::SocketAddr, method: &str, path: &str) -> String {
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        method, path
+    );
+    return exchange(address, request.as_bytes()).unwrap();
+}
+
+// This function returns the value of the header `name` of `response`
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    let (head, _) = response.split_once("\r\n\r\n")?;
+    return head.lines().find_map(|line| {
+        let (field, value) = line.split_once(':')?;
+        match field.eq_ignore_ascii_case(name) {
+            true => Some(value.trim()),
+            false => None,
+        }
+    });
+}
+
+#[test]
+fn answers_head_with_the_get_handler_without_a_body() {
+    for &mode in MODES {
+        let server = serve(mode, WebServer::builder(), serve_routes);
+        // the response to the `HEAD` request ends with its head, the next response follows right
+        // after it
+        let response = exchange(
+            server.address,
+            b"HEAD /items HTTP/1.1\r\nHost: localhost\r\n\r\n\
+GET /items HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK\r\n"),
+            "{:?}: {}",
+            mode,
+            response
+        );
+        assert_eq!(header(&response, "Content-Length"), Some("5"), "{:?}", mode);
+        let (_, rest) = response.split_once("\r\n\r\n").unwrap();
+        assert!(
+            rest.starts_with("HTTP/1.1 200 OK\r\n"),
+            "{:?}: {}",
+            mode,
+            response
+        );
+        assert!(rest.ends_with("\r\n\r\nitems"), "{:?}: {}", mode, response);
+    }
+}
+
+#[test]
+fn answers_options_with_the_allowed_methods() {
+    for &mode in MODES {
+        let server = serve(mode, WebServer::builder(), serve_routes);
+        let response = request(server.address, "OPTIONS", "/items");
+        assert!(
+            response.starts_with("HTTP/1.1 204 "),
+            "{:?}: {}",
+            mode,
+            response
+        );
+        assert_eq!(
+            header(&response, "Allow"),
+            Some("GET, HEAD, OPTIONS, POST"),
+            "{:?}",
+            mode
+        );
+
+        // `OPTIONS *` lists the methods of every route
+        let response = request(server.address, "OPTIONS", "*");
+        assert_eq!(
+            header(&response, "Allow"),
+            Some("GET, HEAD, OPTIONS, POST, PURGE"),
+            "{:?}",
+            mode
+        );
+    }
+}
+
+#[test]
+fn answers_a_method_the_path_has_no_route_for_with_405() {
+    for &mode in MODES {
+        let server = serve(mode, WebServer::builder(), serve_routes);
+        for method in ["DELETE", "PUT", "PURGE"] {
+            let response = request(server.address, method, "/items");
+            assert!(
+                response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+                "{:?} {}: {}",
+                mode,
+                method,
+                response
+            );
+            assert_eq!(
+                header(&response, "Allow"),
+                Some("GET, HEAD, OPTIONS, POST"),
+                "{:?} {}",
+                mode,
+                method
+            );
+        }
+    }
+}
+
+#[test]
+fn answers_an_unknown_extension_method_with_501() {
+    for &mode in MODES {
+        let server = serve(mode, WebServer::builder(), serve_routes);
+        let response = request(server.address, "BREW", "/items");
+        assert!(
+            response.starts_with("HTTP/1.1 501 Not Implemented\r\n"),
+            "{:?}: {}",
+            mode,
+            response
+        );
+        // an extension method with a route is served like any other
+        let response = request(server.address, "PURGE", "/cache");
+        assert!(
+            response.ends_with("\r\n\r\npurged"),
+            "{:?}: {}",
+            mode,
+            response
+        );
+    }
+}
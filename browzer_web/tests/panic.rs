@@ -0,0 +1,64 @@
+// A handler which panics is answered with `500 Internal Server Error`, on the worker threads as
+// well as on the I/O threads, and its connection stops being counted as active
+use browzer_web::{utils::HttpStatusCode, WebServer};
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+// This function serves a route which panics and one which does not with `io_threads` I/O threads,
+// and checks the answers and the counters once the server has shut down
+fn answers_a_panicking_handler(io_threads: usize) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut server = WebServer::builder()
+        .listener(listener)
+        .workers(1)
+        .io_threads(io_threads)
+        .hide_banner(true)
+        .build()
+        .unwrap();
+    let handle = server.handle();
+    server.get("/panic", |_c| panic!("the handler panicked"));
+    server.get("/", |mut c| {
+        c.send_string(HttpStatusCode::OK, "still serving")
+    });
+    let server = thread::spawn(move || server.listen());
+
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        return response;
+    };
+    assert!(get("/panic").starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    assert!(get("/panic").starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    assert!(get("/").ends_with("\r\n\r\nstill serving"));
+
+    handle.shutdown();
+    server.join().unwrap();
+    let stats = handle.stats();
+    assert_eq!(stats.connections, 3);
+    assert_eq!(stats.active_connections, 0);
+}
+
+#[test]
+fn answers_a_panicking_handler_on_the_worker_threads() {
+    answers_a_panicking_handler(0);
+}
+
+#[test]
+fn answers_a_panicking_handler_on_the_io_threads() {
+    answers_a_panicking_handler(1);
+}
@@ -0,0 +1,79 @@
+// Requests a client sends one after the other on a connection, without waiting for the responses,
+// are answered in the order they were sent, however the requests are spread over the reads
+mod common;
+
+use browzer_web::{utils::HttpStatusCode, WebServer};
+use common::{connect, exchange, serve, MODES};
+use std::{
+    io::{Read, Write},
+    thread,
+    time::Duration,
+};
+
+// three requests, the last of which asks for the connection to be closed
+const REQUESTS: &[u8] = b"GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n\
+POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello\
+POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+5\r\nworld\r\n0\r\n\r\n";
+
+// This function splits what the server sent into the bodies of its responses, each of which is
+// expected to have a `Content-Length`
+fn bodies(mut responses: &str) -> Vec<String> {
+    let mut bodies = Vec::new();
+    while !responses.is_empty() {
+        assert!(
+            responses.starts_with("HTTP/1.1 200 OK\r\n"),
+            "{}",
+            responses
+        );
+        let (head, rest) = responses.split_once("\r\n\r\n").unwrap();
+        let length: usize = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                match name.eq_ignore_ascii_case("Content-Length") {
+                    true => value.trim().parse().ok(),
+                    false => None,
+                }
+            })
+            .unwrap();
+        bodies.push(rest[..length].to_string());
+        responses = &rest[length..];
+    }
+    return bodies;
+}
+
+fn serve_routes(server: &mut WebServer) {
+    server.get("/first", |mut c| c.send_string(HttpStatusCode::OK, "first"));
+    server.post("/echo", |mut c| {
+        let body = c.request.body_text();
+        c.send_string(HttpStatusCode::OK, &body)
+    });
+}
+
+#[test]
+fn answers_pipelined_requests_in_order() {
+    for &mode in MODES {
+        let server = serve(mode, WebServer::builder(), serve_routes);
+        let response = exchange(server.address, REQUESTS).unwrap();
+        assert_eq!(bodies(&response), ["first", "hello", "world"], "{:?}", mode);
+    }
+}
+
+#[test]
+fn answers_pipelined_requests_arriving_a_byte_at_a_time() {
+    for &mode in MODES {
+        let server = serve(mode, WebServer::builder(), serve_routes);
+        let mut stream = connect(server.address);
+        stream.set_nodelay(true).unwrap();
+        for byte in REQUESTS {
+            stream.write_all(&[*byte]).unwrap();
+            if *byte == b'\n' {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(bodies(&response), ["first", "hello", "world"], "{:?}", mode);
+    }
+}
@@ -0,0 +1,114 @@
+// A request turned down before it was read in full gets its error response: what the client still
+// sends is read and thrown away before the connection is closed, a reset would make the client
+// lose the response
+mod common;
+
+use browzer_web::{response::Response, utils::HttpStatusCode, WebServer};
+use common::{connect, exchange, serve, MODES};
+use std::{
+    io::{Read, Write},
+    thread,
+    time::Duration,
+};
+
+#[test]
+fn answers_a_body_over_the_limit_while_it_is_still_being_sent() {
+    for &mode in MODES {
+        let server = serve(mode, WebServer::builder().max_body_size(1024), |server| {
+            server.post("/", |mut c| c.send_string(HttpStatusCode::OK, "accepted"));
+        });
+        let body = vec![b'a'; 200 * 1024];
+        let mut request = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&body);
+        let response = exchange(server.address, &request).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+            "{:?}: {}",
+            mode,
+            response
+        );
+    }
+}
+
+#[test]
+fn answers_a_head_over_the_limit_while_it_is_still_being_sent() {
+    for &mode in MODES {
+        let server = serve(mode, WebServer::builder().max_header_size(1024), |server| {
+            server.get("/", |mut c| c.send_string(HttpStatusCode::OK, "accepted"));
+        });
+        let mut request = b"GET / HTTP/1.1\r\nHost: localhost\r\n".to_vec();
+        for i in 0..4096 {
+            request.extend_from_slice(format!("X-Filler-{}: {}\r\n", i, "a".repeat(32)).as_bytes());
+        }
+        request.extend_from_slice(b"\r\n");
+        let response = exchange(server.address, &request).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+            "{:?}: {}",
+            mode,
+            response
+        );
+    }
+}
+
+#[test]
+fn answers_a_turned_down_expectation_while_the_body_is_sent_anyway() {
+    for &mode in MODES {
+        let server = serve(mode, WebServer::builder(), |server| {
+            server.post("/", |mut c| c.send_string(HttpStatusCode::OK, "accepted"));
+            server.expect_continue(|_request| {
+                let status_code = HttpStatusCode::ExpectationFailed;
+                Some(Response::new(
+                    status_code.clone(),
+                    status_code.code().0.to_string(),
+                ))
+            });
+        });
+        // the client does not wait for `100 Continue` before it sends the body
+        let body = vec![b'a'; 200 * 1024];
+        let mut request = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&body);
+        let response = exchange(server.address, &request).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 417 Expectation Failed\r\n"),
+            "{:?}: {}",
+            mode,
+            response
+        );
+    }
+}
+
+#[test]
+fn answers_a_request_timeout_while_the_client_keeps_trickling() {
+    for &mode in MODES {
+        let builder = WebServer::builder().header_read_timeout(Duration::from_millis(300));
+        let server = serve(mode, builder, |server| {
+            server.get("/", |mut c| c.send_string(HttpStatusCode::OK, "accepted"));
+        });
+        // the head is still coming in, a few bytes at a time, well after the timeout ran out. The
+        // server goes on reading them, a reset would make these writes fail
+        let mut stream = connect(server.address);
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        for i in 0..10 {
+            thread::sleep(Duration::from_millis(100));
+            write!(stream, "X-Trickle-{}: a\r\n", i).unwrap();
+        }
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(
+            response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{:?}: {}",
+            mode,
+            response
+        );
+    }
+}
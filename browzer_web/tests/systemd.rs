@@ -0,0 +1,102 @@
+// Socket activation is tested the way systemd does it: a listening socket is passed to a child
+// process (the test binary run again) on file descriptor 3, along with `LISTEN_FDS` and a
+// `LISTEN_PID` naming the child
+#![cfg(unix)]
+
+use browzer_web::{utils::HttpStatusCode, WebServer};
+use std::{
+    env,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream, UdpSocket},
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        process::CommandExt,
+    },
+    process::{Child, Command},
+    time::Duration,
+};
+
+// set in the environment of the child process, the tests below act as the child when it is set
+const CHILD_ENV: &str = "BROWZER_SYSTEMD_TEST_CHILD";
+
+// This function runs the test named `test` again in a child process, with `fd` passed to it as the
+// only socket from systemd
+fn spawn_child(test: &str, fd: RawFd) -> Child {
+    let mut command = Command::new("sh");
+    // the shell sets `LISTEN_PID` to its own process id, which the test binary takes over
+    command
+        .arg("-c")
+        .arg("LISTEN_PID=$$ exec \"$0\" \"$@\"")
+        .arg(env::current_exe().unwrap())
+        .args([test, "--exact", "--nocapture"])
+        .env(CHILD_ENV, "1")
+        .env("LISTEN_FDS", "1");
+    unsafe {
+        command.pre_exec(move || {
+            // `dup2` leaves the new descriptor open on exec, one already at 3 is kept open too
+            let result = match fd {
+                3 => libc::fcntl(fd, libc::F_SETFD, 0),
+                _ => libc::dup2(fd, 3),
+            };
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(());
+        });
+    }
+    return command.spawn().unwrap();
+}
+
+#[test]
+fn serves_a_socket_passed_by_systemd() {
+    if env::var(CHILD_ENV).is_ok() {
+        // the child answers a single request on the socket it was passed and shuts down
+        let mut server = WebServer::builder()
+            .systemd_socket()
+            .hide_banner(true)
+            .build()
+            .unwrap();
+        let handle = server.handle();
+        server.get("/", move |mut c| {
+            handle.shutdown();
+            c.send_string(HttpStatusCode::OK, "served by the child")
+        });
+        server.listen();
+        return;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut child = spawn_child("serves_a_socket_passed_by_systemd", listener.as_raw_fd());
+
+    // the connection waits in the backlog of the socket until the child accepts it
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nserved by the child"));
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn rejects_a_socket_which_is_not_listening() {
+    if env::var(CHILD_ENV).is_ok() {
+        // building the server fails, and the descriptor it was passed is closed
+        assert!(WebServer::builder().systemd_socket().build().is_err());
+        assert_eq!(unsafe { libc::fcntl(3, libc::F_GETFD) }, -1);
+        return;
+    }
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut child = spawn_child(
+        "rejects_a_socket_which_is_not_listening",
+        socket.as_raw_fd(),
+    );
+    assert!(child.wait().unwrap().success());
+}
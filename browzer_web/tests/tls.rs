@@ -0,0 +1,80 @@
+// TLS is tested end to end: the server is given a freshly generated self-signed certificate for
+// `localhost`, and a rustls client which trusts only that certificate talks to it
+#![cfg(feature = "tls")]
+
+use browzer_web::{utils::HttpStatusCode, WebServer};
+use std::{
+    env, fs,
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    process,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+#[test]
+fn fills_in_the_tls_session_of_a_request() {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let directory = env::temp_dir().join(format!("browzer-tls-test-{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let cert_path = directory.join("cert.pem");
+    let key_path = directory.join("key.pem");
+    fs::write(&cert_path, certificate.cert.pem()).unwrap();
+    fs::write(&key_path, certificate.key_pair.serialize_pem()).unwrap();
+
+    // the server answers a single request with what it knows about the TLS session and shuts down
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut server = WebServer::builder()
+        .listener(listener)
+        .tls(&cert_path, &key_path)
+        .hide_banner(true)
+        .build()
+        .unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+    let handle = server.handle();
+    server.get("/", move |mut c| {
+        handle.shutdown();
+        let body = match &c.request.tls {
+            Some(tls) => format!(
+                "{} {} {:?}",
+                tls.protocol_version, tls.cipher_suite, tls.server_name
+            ),
+            None => "no TLS session".to_string(),
+        };
+        c.send_string(HttpStatusCode::OK, &body)
+    });
+    let server = thread::spawn(move || server.listen());
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certificate.cert.der().clone()).unwrap();
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let connection =
+        rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+    let socket = TcpStream::connect(address).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut stream = rustls::StreamOwned::new(connection, socket);
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response) {
+        Ok(_) => {}
+        // the connection may be closed without a `close_notify` after the response
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {}
+        Err(e) => panic!("reading the response failed: {}", e),
+    }
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nTLSv1_3 TLS13_AES_256_GCM_SHA384 Some(\"localhost\")"));
+    server.join().unwrap();
+}
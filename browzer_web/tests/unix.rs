@@ -0,0 +1,68 @@
+// A Unix domain socket created by the builder gets its permissions as it is bound, and is removed
+// again once the server shuts down. Only a socket is ever removed from the socket path
+#![cfg(unix)]
+
+use browzer_web::{utils::HttpStatusCode, WebServer};
+use std::{
+    env, fs,
+    io::{Read, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::PathBuf,
+    process, thread,
+};
+
+// This function returns a new directory of its own for the test named `test` to put its socket in
+fn socket_dir(test: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("browzer-{}-{}", test, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    return dir;
+}
+
+#[test]
+fn serves_a_socket_created_with_its_permissions_and_removes_it() {
+    let dir = socket_dir("permissions");
+    let path = dir.join("server.sock");
+    let mut server = WebServer::builder()
+        .bind_unix(&path)
+        .unix_socket_permissions(0o600)
+        .hide_banner(true)
+        .build()
+        .unwrap();
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+    let handle = server.handle();
+    server.get("/", |mut c| {
+        c.send_string(HttpStatusCode::OK, "over a socket")
+    });
+    let server = thread::spawn(move || server.listen());
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("\r\n\r\nover a socket"), "{}", response);
+
+    handle.shutdown();
+    server.join().unwrap();
+    assert!(fs::symlink_metadata(&path).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn leaves_a_file_which_is_not_a_socket_alone() {
+    let dir = socket_dir("not-a-socket");
+    let path = dir.join("server.sock");
+    fs::write(&path, "not a socket").unwrap();
+    assert!(WebServer::builder()
+        .bind_unix(&path)
+        .hide_banner(true)
+        .build()
+        .is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+    fs::remove_dir_all(&dir).unwrap();
+}
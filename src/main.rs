@@ -1,13 +1,51 @@
 mod utils;
 
 use browzer_web;
+use std::process;
 
 fn main() {
-    let mut server = browzer_web::WebServer::new(format!("0.0.0.0:{}", utils::PORT), 5);
+    let mut server = match browzer_web::WebServer::builder()
+        .bind(&format!("0.0.0.0:{}", utils::PORT))
+        .workers(5)
+        .build()
+    {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to start the server, Error: {}", e);
+            process::exit(1);
+        }
+    };
 
     server.get("/", |mut c| {
         return c.send_string(browzer_web::utils::HttpStatusCode::OK, "Hello,World!");
     });
 
+    // drain the server on SIGINT/SIGTERM instead of dying in the middle of responses, a second
+    // signal while the server is draining exits right away
+    let handle = server.handle();
+    match ctrlc::set_handler(move || {
+        if handle.is_shutdown() {
+            process::exit(130);
+        }
+        println!("-----> Shutting down, waiting for in-flight requests to finish");
+        handle.shutdown();
+    }) {
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to install the signal handler, Error: {}", e),
+    };
+
     server.listen();
+
+    let stats = server.handle().stats();
+    println!(
+        "-----> HTTP server stopped, served {} requests over {} connections",
+        stats.requests, stats.connections
+    );
+    if stats.active_connections > 0 {
+        eprintln!(
+            "{} connections were still being served when the shutdown timed out",
+            stats.active_connections
+        );
+        process::exit(1);
+    }
 }